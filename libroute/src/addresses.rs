@@ -1,6 +1,7 @@
 use std::mem;
 use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6};
 
+use ipnetwork::{ip_mask_to_prefix, IpNetwork};
 use nix::libc::{
     ifa_msghdr,
     sockaddr,
@@ -119,7 +120,11 @@ impl std::fmt::Display for AddressFlags {
     }
 }
 
-#[derive(Debug)]
+/// Serialised as an object tagged by `family`:
+/// - `{"family": "inet", "address": "192.0.2.1"}`
+/// - `{"family": "inet6", "address": "fe80::1", "scope_id": 4}`
+/// - `{"family": "link", "index": 4, "name": "en0", "mac": "aa:bb:cc:dd:ee:ff"}`
+#[derive(Clone, Debug)]
 pub enum SockAddr {
     V4(SocketAddrV4),
     V6(SocketAddrV6),
@@ -127,6 +132,15 @@ pub enum SockAddr {
 }
 
 impl SockAddr {
+    /// The IP address held by this sockaddr, if it is an internet address.
+    pub fn ip(&self) -> Option<IpAddr> {
+        match self {
+            Self::V4(a) => Some(IpAddr::V4(*a.ip())),
+            Self::V6(a) => Some(IpAddr::V6(*a.ip())),
+            Self::Link(_) => None,
+        }
+    }
+
     pub(crate) fn from_raw(data: &[u8]) -> Result<(Option<Self>, usize), AddressParseError> {
         if data.is_empty() {
             return Err(AddressParseError::DataEmpty);
@@ -155,8 +169,7 @@ impl SockAddr {
             }
             AF_LINK => {
                 log::debug!("Data link(?) address");
-                let ptr: *const sockaddr_dl = data.as_ptr() as *const _;
-                let v = <_ as NetStruct<_>>::from_raw(ptr)?;
+                let (v, len) = parse_link(data)?;
                 (Some(SockAddr::Link(v)), len)
             }
             _ => {
@@ -168,22 +181,83 @@ impl SockAddr {
     }
 }
 
+/// Sockaddrs in routing messages are padded out to a 32-bit boundary, and
+/// zero-length sockaddrs still take up 4 bytes.
+pub(crate) fn sa_roundup(len: usize) -> usize {
+    const ALIGN: usize = std::mem::size_of::<u32>();
+    match len {
+        0 => ALIGN,
+        n => 1 + ((n - 1) | (ALIGN - 1)),
+    }
+}
+
+/// Parses a netmask sockaddr.
+///
+/// The kernel trims netmasks down to their last non-zero byte, so `sa_len` is
+/// usually shorter than the full sockaddr and `sa_family` may not be set at
+/// all. Nobody in the past 10 years except for this guy seems to have
+/// noticed: https://stackoverflow.com/q/33638206
+///
+/// Missing bytes are zero, and if no family is given we take it from `sample`
+/// (another address from the same message). If there is no sample either, no
+/// netmask is returned, but the length is still given so it can be skipped.
+pub(crate) fn parse_netmask(
+    data: &[u8],
+    sample: Option<&SockAddr>,
+) -> Result<(Option<IpAddr>, usize), AddressParseError> {
+    if data.len() < 2 {
+        return Err(AddressParseError::PartialData);
+    }
+
+    let len = data[0] as usize;
+    let family = match data[1] as i32 {
+        f @ (AF_INET | AF_INET6) => f,
+        _ => match sample {
+            Some(SockAddr::V4(_)) => AF_INET,
+            Some(SockAddr::V6(_)) => AF_INET6,
+            _ => return Ok((None, sa_roundup(len))),
+        },
+    };
+    log::trace!("netmask family: {family}, len: {len}");
+
+    // Offset of sin_addr/sin6_addr within their sockaddr structs
+    let (start, size) = match family {
+        AF_INET => (4, 4),
+        _ => (8, 16),
+    };
+    let end = len.min(data.len());
+    let mut bytes = [0u8; 16];
+    if end > start {
+        let n = (end - start).min(size);
+        bytes[..n].copy_from_slice(&data[start..start + n]);
+    }
+
+    let mask = match family {
+        AF_INET => IpAddr::V4(Ipv4Addr::new(bytes[0], bytes[1], bytes[2], bytes[3])),
+        _ => IpAddr::V6(Ipv6Addr::from(bytes)),
+    };
+
+    Ok((Some(mask), sa_roundup(len)))
+}
+
 pub fn parse_link(data: &[u8]) -> Result<(DataLinkAddr, usize), AddressParseError> {
-    if data.is_empty() {
+    if data.len() < 2 {
         return Err(AddressParseError::DataEmpty);
     }
-    // TODO: SAFETY: We're trusting that this truly is an accurate
-    // struct as passed from the kernel. this should probably be removed from
-    // the rest of the common parsing logic.
-    let sockaddr_dl_ptr: *const sockaddr_dl = data.as_ptr() as *const _;
-    let family = unsafe { (*sockaddr_dl_ptr).sdl_family as i32 };
-    assert!(family == AF_LINK, "sdl_family must be AF_LINK");
     // NOTE: we have to get this here, because otherwise we can't skip over
     // unsupported chunks when parsing
-    let len = unsafe { (*sockaddr_dl_ptr).sdl_len as usize };
+    let len = data[0] as usize;
+    let family = data[1] as i32;
+    assert!(family == AF_LINK, "sdl_family must be AF_LINK");
     log::trace!("family: {family}, len: {len}, data: {data:?}");
+    // Everything up to sdl_data has to be there, as does everything sdl_len
+    // says follows it.
+    if data.len() < len || len < DataLinkAddr::DATA_OFFSET {
+        return Err(AddressParseError::PartialData);
+    }
 
-    let addr = unsafe { DataLinkAddr::from_raw(sockaddr_dl_ptr) };
+    // SAFETY: we've checked that the sdl_len bytes it covers are in the slice
+    let addr = unsafe { DataLinkAddr::from_raw(data.as_ptr() as *const sockaddr_dl) };
     Ok((addr, len))
 }
 
@@ -220,44 +294,64 @@ pub fn parse_ip(data: &[u8]) -> Result<(SocketAddr, usize), AddressParseError> {
     Ok((res, len))
 }
 
-#[derive(Debug)]
+/// Serialised as `{"index": 4, "name": "en0", "mac": "aa:bb:cc:dd:ee:ff"}`.
+/// `mac` is `null` if the link has no link-layer address.
+#[derive(Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
 pub struct DataLinkAddr {
     pub index: u16,
     // Leaving the gigantic enum of this out for now
     // pub interface_type: InterfaceType,
+    #[cfg_attr(
+        feature = "serde",
+        serde(rename = "mac", serialize_with = "crate::ser::mac")
+    )]
     pub link_layer_addr: Vec<u8>,
+    #[cfg_attr(feature = "serde", serde(rename = "name"))]
     pub interface_name: String,
     // Discarding link layer selector
 }
 
+/// Formats a link-layer address as lower-case colon-separated hex, e.g.
+/// `aa:bb:cc:dd:ee:ff`.
+pub fn format_mac(addr: &[u8]) -> String {
+    let strs: Vec<String> = addr.iter().map(|b| format!("{b:02x}")).collect();
+    strs.join(":")
+}
+
 impl DataLinkAddr {
-    fn format_addr(&self) -> String {
-        let strs: Vec<String> = self
-            .link_layer_addr
-            .iter()
-            .map(|b| format!("{:02X}", b))
-            .collect();
-        strs.join(":")
-    }
+    /// Where `sdl_data` starts within a sockaddr_dl
+    const DATA_OFFSET: usize = mem::size_of::<sockaddr_dl>() - 12;
 
     /// # Safety
     /// This should only be called with a sockaddr_dl pointer from the
-    /// kernel
+    /// kernel, which has at least `sdl_len` bytes (and at least up to
+    /// `sdl_data`) behind it. It doesn't need to be aligned.
     pub unsafe fn from_raw(ptr: *const sockaddr_dl) -> Self {
-        let addr = *ptr;
+        // NOTE: sockaddr_dl is 20 bytes, but sdl_len may be shorter than
+        // that, so only copy what's there.
+        let sdl_len = *(ptr as *const u8) as usize;
+        let mut addr: sockaddr_dl = mem::zeroed();
+        std::ptr::copy_nonoverlapping(
+            ptr as *const u8,
+            &mut addr as *mut sockaddr_dl as *mut u8,
+            sdl_len.clamp(Self::DATA_OFFSET, mem::size_of::<sockaddr_dl>()),
+        );
 
         assert!(addr.sdl_family as i32 == AF_LINK);
 
         let index = addr.sdl_index;
-        // NOTE: convert our [i8; 12] to a [u8; 12]. This is raw data that is
-        // expected to be a C string.
-        let data: [u8; 12] = mem::transmute(addr.sdl_data);
-
-        let ll_addr_start = addr.sdl_nlen as usize;
-        let ll_addr_end = ll_addr_start + addr.sdl_alen as usize;
+        // NOTE: sdl_data is only declared as 12 bytes, but the name and
+        // address carry on for as long as sdl_len says (e.g. "bridge0" plus a
+        // MAC doesn't fit in 12 bytes).
+        let data_len = sdl_len.saturating_sub(Self::DATA_OFFSET);
+        let data = std::slice::from_raw_parts((ptr as *const u8).add(Self::DATA_OFFSET), data_len);
+
+        let ll_addr_start = (addr.sdl_nlen as usize).min(data_len);
+        let ll_addr_end = (ll_addr_start + addr.sdl_alen as usize).min(data_len);
         let link_layer_bytes = &data[ll_addr_start..ll_addr_end];
         let link_layer_addr = Vec::from(link_layer_bytes);
-        let name_slice = &data[..addr.sdl_nlen as usize];
+        let name_slice = &data[..ll_addr_start];
         let interface_name = String::from_utf8_lossy(name_slice).to_string().clone();
 
         DataLinkAddr {
@@ -275,7 +369,7 @@ impl DataLinkAddr {
         if name: {}
         ",
             self.index,
-            self.format_addr(),
+            format_mac(&self.link_layer_addr),
             self.interface_name
         )
     }
@@ -393,6 +487,45 @@ pub enum AddressParseError {
     NetmaskWithoutKnownProto,
 }
 
+#[derive(thiserror::Error, Debug)]
+pub enum NetworkError {
+    #[error("netmask {0} is not contiguous")]
+    NonContiguousMask(IpAddr),
+    #[error("netmask {mask} is of a different family to address {addr}")]
+    FamilyMismatch { addr: IpAddr, mask: IpAddr },
+}
+
+/// Combines an address with an (optional) netmask. A missing netmask means
+/// this is a host address, so the full-length prefix is used.
+fn to_network(
+    addr: &SockAddr,
+    netmask: Option<&IpAddr>,
+) -> Result<Option<IpNetwork>, NetworkError> {
+    let ip = match addr.ip() {
+        Some(ip) => ip,
+        None => return Ok(None),
+    };
+
+    let prefix = match (ip, netmask) {
+        (IpAddr::V4(_), None) => 32,
+        (IpAddr::V6(_), None) => 128,
+        (IpAddr::V4(_), Some(mask @ IpAddr::V4(_)))
+        | (IpAddr::V6(_), Some(mask @ IpAddr::V6(_))) => {
+            ip_mask_to_prefix(*mask).map_err(|_| NetworkError::NonContiguousMask(*mask))?
+        }
+        (_, Some(mask)) => {
+            return Err(NetworkError::FamilyMismatch {
+                addr: ip,
+                mask: *mask,
+            })
+        }
+    };
+
+    // NOTE: this can only fail on an invalid prefix, which we've ruled out
+    // above.
+    Ok(Some(IpNetwork::new(ip, prefix).unwrap()))
+}
+
 pub(crate) fn parse_address(data: &[u8]) -> Result<(Option<SockAddr>, usize), AddressParseError> {
     if data.is_empty() {
         return Err(AddressParseError::DataEmpty);
@@ -401,7 +534,7 @@ pub(crate) fn parse_address(data: &[u8]) -> Result<(Option<SockAddr>, usize), Ad
     // TODO: SAFETY: We're trusting that this truly is an accurate
     // struct as passed from the kernel. this should probably be removed from
     // the rest of the common parsing logic.
-    let (res, len) = SockAddr::from_raw(data).unwrap();
+    let (res, len) = SockAddr::from_raw(data)?;
     match res.as_ref() {
         Some(d) => log::trace!("read {:?}, ({len} bytes) from data", d),
         None => log::trace!("empty read from data ({len} bytes skipped)"),
@@ -410,11 +543,57 @@ pub(crate) fn parse_address(data: &[u8]) -> Result<(Option<SockAddr>, usize), Ad
 }
 
 #[derive(Debug)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize),
+    serde(rename_all = "snake_case")
+)]
 pub enum AddressOperation {
     Add,
     Delete,
 }
 
+/// Names for each `RTF_*` flag, as used in `names()` and serialisation.
+pub(crate) const ROUTE_FLAG_NAMES: &[(i32, &str)] = &[
+    (RTF_UP, "up"),
+    (RTF_GATEWAY, "gateway"),
+    (RTF_HOST, "host"),
+    (RTF_REJECT, "reject"),
+    (RTF_DYNAMIC, "dynamic"),
+    (RTF_MODIFIED, "modified"),
+    (RTF_DONE, "done"),
+    (RTF_DELCLONE, "delclone"),
+    (RTF_CLONING, "cloning"),
+    (RTF_XRESOLVE, "xresolve"),
+    (RTF_LLINFO, "llinfo"),
+    (RTF_STATIC, "static"),
+    (RTF_BLACKHOLE, "blackhole"),
+    (RTF_NOIFREF, "noifref"),
+    (RTF_PROTO2, "proto2"),
+    (RTF_PROTO1, "proto1"),
+    (RTF_PRCLONING, "prcloning"),
+    (RTF_WASCLONED, "wascloned"),
+    (RTF_PROTO3, "proto3"),
+    (RTF_LOCAL, "local"),
+    (RTF_BROADCAST, "broadcast"),
+    (RTF_MULTICAST, "multicast"),
+    (RTF_IFSCOPE, "ifscope"),
+    (RTF_CONDEMNED, "condemned"),
+    (RTF_IFREF, "ifref"),
+    (RTF_PROXY, "proxy"),
+    (RTF_ROUTER, "router"),
+    (RTF_DEAD, "dead"),
+];
+
+/// Lists the names of all flags in `table` which are set in `bits`.
+pub(crate) fn flag_names(bits: i32, table: &[(i32, &'static str)]) -> Vec<&'static str> {
+    table
+        .iter()
+        .filter(|(flag, _)| bits & flag != 0)
+        .map(|(_, name)| *name)
+        .collect()
+}
+
 #[derive(Debug)]
 pub struct AddressInfoFlags(i32);
 
@@ -423,6 +602,11 @@ impl AddressInfoFlags {
         Self(val)
     }
 
+    /// Names of all flags which are set, e.g. `["up", "gateway"]`.
+    pub fn names(&self) -> Vec<&'static str> {
+        flag_names(self.0, ROUTE_FLAG_NAMES)
+    }
+
     /* route usable */
     pub fn is_up(&self) -> bool {
         self.0 & RTF_UP != 0
@@ -543,18 +727,24 @@ impl AddressInfoFlags {
     // }
 }
 
+/// Missing addresses are serialised as `null`. Masks and broadcast addresses
+/// are plain address strings.
 #[derive(Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
 pub struct AddressSet {
     pub destination: Option<SockAddr>,
     pub gateway: Option<SockAddr>,
     pub netmask: Option<IpAddr>,
+    #[cfg_attr(feature = "serde", serde(serialize_with = "crate::ser::socket_ip"))]
     pub genmask: Option<SocketAddr>,
+    #[cfg_attr(feature = "serde", serde(serialize_with = "crate::ser::socket_ip"))]
     pub broadcast: Option<SocketAddr>,
     pub interface_addr: Option<SockAddr>,
     pub interface_link: Option<DataLinkAddr>,
 }
 
 #[derive(Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
 pub struct AddressInfo {
     pub operation: AddressOperation,
     pub index: u16,
@@ -568,6 +758,7 @@ impl AddressSet {
         log::debug!("parsing addresses, data of length {}", data.len());
         log::debug!("flags: {}", flags);
         let mut offset = 0;
+        let mut netmask_data = None;
 
         let n = data.len();
 
@@ -601,7 +792,7 @@ impl AddressSet {
             let (dest, len) = parse_address(&data[offset..])?;
             info.destination = dest;
             log::trace!("dest: {:?}", info.destination);
-            offset += len;
+            offset += sa_roundup(len);
         }
 
         if flags.has_gateway() {
@@ -614,7 +805,7 @@ impl AddressSet {
             let (gw, len) = parse_address(&data[offset..])?;
             info.gateway = gw;
             log::trace!("gw: {:?}", info.gateway);
-            offset += len;
+            offset += sa_roundup(len);
         }
 
         if flags.has_netmask() {
@@ -625,53 +816,17 @@ impl AddressSet {
 
             // From reading the source code...the netmask can be sent
             // in different formats, depending on the type of event we receive.
-            //
-            // `route` assumes this always has a sa_family for GET events
             log::trace!("parsing netmask, offset {offset}");
             log::trace!("netmask data: {:?}", &data[offset..]);
 
-            let (sock_addr, len) = match parse_ip(&data[offset..]) {
-                Ok((addr, len)) => match addr {
-                    SocketAddr::V4(a) => (IpAddr::V4(*a.ip()), len),
-                    SocketAddr::V6(a) => (IpAddr::V6(*a.ip()), len),
-                },
-                Err(e) => {
-                    // Don't want to actually have this here, it gets shown by
-                    // default.
-                    // log::warn!("fallback case");
-                    // NOTE: Sometimes, a netmask is not given to us as a
-                    // sockaddr, but rather just as a raw IP. For some reason,
-                    // nobody in the past 10 years except for this guy seems
-                    // to have noticed: https://stackoverflow.com/q/33638206
-                    //
-                    // Have not yet run into this, though:
-                    // https://github.com/FRRouting/frr/blob/5c30b2e21205ecc60615b633dbc4714bae70a676/zebra/kernel_socket.c#L250-L253
-                    let sample = info.destination.as_ref().or(info.gateway.as_ref());
-                    match sample {
-                        Some(SockAddr::V4(_)) => {
-                            const N: usize = 4; // 4 bytes in ipv4
-                            let mut d = [0u8; N];
-                            d.clone_from_slice(&data[offset..offset + N]);
-
-                            // let addr = Ipv4Addr::from(d);
-                            (IpAddr::V4(d.into()), N)
-                        }
-                        Some(SockAddr::V6(_)) => {
-                            const N: usize = 16; // 16 bytes in ipv6
-                            let mut d = [0u8; N];
-                            d.clone_from_slice(&data[offset..offset + N]);
-                            (IpAddr::V6(d.into()), N)
-                        }
-                        Some(_) => panic!("netmask for link addr thingy"),
-                        None => {
-                            return Err(e);
-                            // return Err(AddressParseError::NetmaskWithoutKnownProto);
-                        }
-                    }
-                }
-            };
-
-            info.netmask = Some(sock_addr);
+            let sample = info.destination.as_ref().or(info.gateway.as_ref());
+            let (netmask, len) = parse_netmask(&data[offset..], sample)?;
+            if netmask.is_none() {
+                // Address messages carry no destination, so we can only
+                // tell the family once we've seen the interface address.
+                netmask_data = Some(&data[offset..]);
+            }
+            info.netmask = netmask;
             offset += len;
         }
 
@@ -684,7 +839,7 @@ impl AddressSet {
             log::trace!("parsing genmask, offset {offset}");
             let (genmask, len) = parse_ip(&data[offset..])?;
             info.genmask = Some(genmask);
-            offset += len;
+            offset += sa_roundup(len);
         }
 
         if flags.has_interface_link() {
@@ -696,7 +851,7 @@ impl AddressSet {
             log::trace!("parsing link, offset {offset}");
             let (if_link, len) = parse_link(&data[offset..])?;
             info.interface_link = Some(if_link);
-            offset += len;
+            offset += sa_roundup(len);
         }
 
         if flags.has_interface_address() {
@@ -708,7 +863,12 @@ impl AddressSet {
             log::trace!("parsing addr, offset {offset}");
             let (interface_addr, len) = parse_address(&data[offset..])?;
             info.interface_addr = interface_addr;
-            offset += len;
+            offset += sa_roundup(len);
+
+            if let Some(mask_data) = netmask_data {
+                let (netmask, _) = parse_netmask(mask_data, info.interface_addr.as_ref())?;
+                info.netmask = netmask;
+            }
         }
 
         if flags.has_author() {
@@ -719,7 +879,7 @@ impl AddressSet {
 
             log::trace!("parsing auth, offset {offset}");
             let (_, len) = parse_address(&data[offset..])?;
-            offset += len;
+            offset += sa_roundup(len);
         }
 
         if flags.has_brd() {
@@ -735,6 +895,29 @@ impl AddressSet {
         Ok(info)
     }
 
+    /// The network this message's destination covers, e.g. for a route.
+    ///
+    /// Messages without a netmask (such as host routes) are treated as a
+    /// single host. Returns `None` if there is no IP destination.
+    pub fn destination_network(&self) -> Result<Option<IpNetwork>, NetworkError> {
+        match &self.destination {
+            Some(dest) => to_network(dest, self.netmask.as_ref()),
+            None => Ok(None),
+        }
+    }
+
+    /// The network the interface address belongs to, e.g. for a new address.
+    ///
+    /// The address itself is kept (rather than the network address), so
+    /// `.ip()` still gives the interface address. Returns `None` if there is
+    /// no IP interface address.
+    pub fn interface_network(&self) -> Result<Option<IpNetwork>, NetworkError> {
+        match &self.interface_addr {
+            Some(addr) => to_network(addr, self.netmask.as_ref()),
+            None => Ok(None),
+        }
+    }
+
     pub fn print_self(&self) -> String {
         format!(
             "
@@ -823,3 +1006,189 @@ impl AddressInfo {
         }))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn v4_sample() -> SockAddr {
+        SockAddr::V4(SocketAddrV4::new(Ipv4Addr::new(192, 0, 2, 1), 0))
+    }
+
+    #[test]
+    fn full_ipv4_netmask() {
+        let mut data = [0u8; 16];
+        data[0] = 16;
+        data[1] = AF_INET as u8;
+        data[4..8].copy_from_slice(&[255, 255, 255, 0]);
+
+        let (mask, len) = parse_netmask(&data, None).unwrap();
+        assert_eq!(mask, Some(IpAddr::V4(Ipv4Addr::new(255, 255, 255, 0))));
+        assert_eq!(len, 16);
+    }
+
+    #[test]
+    fn trimmed_netmask_takes_family_from_sample() {
+        // 255.0.0.0, trimmed after its last non-zero byte, without a family
+        let data = [5, 0, 0, 0, 255, 0, 0, 0];
+
+        let (mask, len) = parse_netmask(&data, Some(&v4_sample())).unwrap();
+        assert_eq!(mask, Some(IpAddr::V4(Ipv4Addr::new(255, 0, 0, 0))));
+        assert_eq!(len, 8);
+    }
+
+    #[test]
+    fn trimmed_ipv6_netmask() {
+        // A /64, which ends half way through sin6_addr
+        let mut data = [0u8; 16];
+        data[0] = 16;
+        data[1] = AF_INET6 as u8;
+        data[8..16].fill(0xff);
+
+        let (mask, len) = parse_netmask(&data, None).unwrap();
+        let want: Ipv6Addr = "ffff:ffff:ffff:ffff::".parse().unwrap();
+        assert_eq!(mask, Some(IpAddr::V6(want)));
+        assert_eq!(len, 16);
+    }
+
+    #[test]
+    fn empty_netmask_without_sample_is_skipped() {
+        let (mask, len) = parse_netmask(&[0, 0, 0, 0], None).unwrap();
+        assert_eq!(mask, None);
+        assert_eq!(len, 4);
+    }
+
+    #[test]
+    fn netmask_too_short() {
+        assert!(matches!(
+            parse_netmask(&[5], None),
+            Err(AddressParseError::PartialData)
+        ));
+    }
+
+    /// A sockaddr_dl for `name` and `mac` on interface 4.
+    fn sockaddr_dl(name: &str, mac: &[u8]) -> Vec<u8> {
+        let mut data = vec![0, AF_LINK as u8];
+        data.extend_from_slice(&4u16.to_ne_bytes());
+        // sdl_type, sdl_nlen, sdl_alen, sdl_slen
+        data.extend_from_slice(&[6, name.len() as u8, mac.len() as u8, 0]);
+        data.extend_from_slice(name.as_bytes());
+        data.extend_from_slice(mac);
+        // The struct is at least 20 bytes
+        data.resize(data.len().max(20), 0);
+        data[0] = data.len() as u8;
+        data
+    }
+
+    #[test]
+    fn links() {
+        let mac = [0xaa, 0xbb, 0xcc, 0x00, 0x11, 0x22];
+        let data = sockaddr_dl("en0", &mac);
+        let (link, len) = parse_link(&data).unwrap();
+        assert_eq!(len, 20);
+        assert_eq!(link.index, 4);
+        assert_eq!(link.interface_name, "en0");
+        assert_eq!(link.link_layer_addr, mac);
+    }
+
+    #[test]
+    fn links_longer_than_sdl_data() {
+        // "bridge0" and a MAC don't fit in the declared 12 bytes
+        let mac = [0xaa, 0xbb, 0xcc, 0x00, 0x11, 0x22];
+        let data = sockaddr_dl("bridge0", &mac);
+        let (link, len) = parse_link(&data).unwrap();
+        assert_eq!(len, 21);
+        assert_eq!(link.interface_name, "bridge0");
+        assert_eq!(link.link_layer_addr, mac);
+    }
+
+    #[test]
+    fn truncated_links() {
+        let data = sockaddr_dl("bridge0", &[0xaa, 0xbb, 0xcc, 0x00, 0x11, 0x22]);
+        for len in [2, 8, 20] {
+            assert!(
+                matches!(
+                    parse_link(&data[..len]),
+                    Err(AddressParseError::PartialData)
+                ),
+                "{len} bytes"
+            );
+        }
+    }
+
+    #[test]
+    fn short_links() {
+        // Only up to sdl_data, with no name or address
+        let mut data = sockaddr_dl("", &[]);
+        data[0] = 8;
+        let (link, len) = parse_link(&data[..8]).unwrap();
+        assert_eq!(len, 8);
+        assert_eq!(link.interface_name, "");
+        assert!(link.link_layer_addr.is_empty());
+    }
+
+    fn addresses(destination: IpAddr, netmask: Option<IpAddr>) -> AddressSet {
+        let destination = match destination {
+            IpAddr::V4(ip) => SockAddr::V4(SocketAddrV4::new(ip, 0)),
+            IpAddr::V6(ip) => SockAddr::V6(SocketAddrV6::new(ip, 0, 0, 0)),
+        };
+        AddressSet {
+            destination: Some(destination.clone()),
+            gateway: None,
+            netmask,
+            genmask: None,
+            broadcast: None,
+            interface_addr: Some(destination),
+            interface_link: None,
+        }
+    }
+
+    #[test]
+    fn networks() {
+        let addrs = addresses("192.0.2.10".parse().unwrap(), "255.255.255.0".parse().ok());
+        let want: IpNetwork = "192.0.2.10/24".parse().unwrap();
+        assert_eq!(addrs.destination_network().unwrap(), Some(want));
+        // The interface address is kept, rather than the network address
+        assert_eq!(addrs.interface_network().unwrap().unwrap().ip(), want.ip());
+    }
+
+    #[test]
+    fn default_route_network() {
+        let addrs = addresses("0.0.0.0".parse().unwrap(), "0.0.0.0".parse().ok());
+        let want: IpNetwork = "0.0.0.0/0".parse().unwrap();
+        assert_eq!(addrs.destination_network().unwrap(), Some(want));
+    }
+
+    #[test]
+    fn host_route_network() {
+        let addrs = addresses("192.0.2.10".parse().unwrap(), None);
+        let want: IpNetwork = "192.0.2.10/32".parse().unwrap();
+        assert_eq!(addrs.destination_network().unwrap(), Some(want));
+
+        let addrs = addresses("2001:db8::1".parse().unwrap(), None);
+        let want: IpNetwork = "2001:db8::1/128".parse().unwrap();
+        assert_eq!(addrs.destination_network().unwrap(), Some(want));
+    }
+
+    #[test]
+    fn non_contiguous_mask() {
+        let addrs = addresses("192.0.2.0".parse().unwrap(), "255.0.255.0".parse().ok());
+        assert!(matches!(
+            addrs.destination_network(),
+            Err(NetworkError::NonContiguousMask(_))
+        ));
+        assert!(matches!(
+            addrs.interface_network(),
+            Err(NetworkError::NonContiguousMask(_))
+        ));
+    }
+
+    #[test]
+    fn mask_of_another_family() {
+        let addrs = addresses("2001:db8::".parse().unwrap(), "255.255.255.0".parse().ok());
+        assert!(matches!(
+            addrs.destination_network(),
+            Err(NetworkError::FamilyMismatch { .. })
+        ));
+    }
+}
@@ -1,6 +1,5 @@
 use nix::libc::{
     if_indextoname,
-    rt_msghdr,
     IFNAMSIZ,
     RTM_ADD,
     RTM_CHANGE,
@@ -19,7 +18,14 @@ use crate::addresses::{AddressInfo, AddressParseError, AddressSet};
 use crate::link::LinkInfo;
 use crate::route::RouteInfo;
 
+/// Serialised as the inner message, with an added `kind` field of `route`,
+/// `link` or `address`.
 #[derive(Debug)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize),
+    serde(tag = "kind", rename_all = "snake_case")
+)]
 pub enum Header {
     Route(RouteInfo),
     Link(LinkInfo),
@@ -52,39 +58,37 @@ impl Header {
     }
 
     pub(crate) fn from_raw(data: &[u8]) -> Result<Option<Self>, AddressParseError> {
-        // Get the header
-        let hdr_ptr: *const rt_msghdr = data.as_ptr() as *const _;
-        // SAFETY: we depend on this being a byte slice received directly
-        // from the kernel. The privacy of this function should allow us to
-        // ensure this.
-        let hdr = unsafe { *hdr_ptr };
+        // NOTE: Every message type shares the leading length, version and
+        // type fields of rt_msghdr, but some (e.g. ifa_msghdr) are much
+        // shorter than it, so we only read those fields here.
+        if data.len() < 4 {
+            return Err(AddressParseError::PartialData);
+        }
 
-        let sz = hdr.rtm_msglen as usize;
+        let sz = u16::from_ne_bytes([data[0], data[1]]) as usize;
         let n = data.len();
         log::trace!("size={sz}, data.len()={n}");
         if sz != n {
             panic!("partial data read: size={sz}, data.len()={n}");
         }
 
-        let seq = hdr.rtm_seq;
-        let pid = hdr.rtm_pid;
-        let hdr_type = hdr.rtm_type as i32;
-        log::trace!("type: {hdr_type}, seq: {seq}, pid: {pid}");
+        let hdr_type = data[3] as i32;
+        log::trace!("type: {hdr_type}");
         match hdr_type {
             RTM_ADD | RTM_DELETE | RTM_CHANGE | RTM_GET | RTM_GET2 | RTM_OLDADD | RTM_OLDDEL => {
-                log::trace!("parsing route (type {})", hdr.rtm_type);
+                log::trace!("parsing route (type {hdr_type})");
                 RouteInfo::from_raw(data).map(|opt| opt.map(Self::Route))
             }
             RTM_IFINFO | RTM_IFINFO2 => {
-                log::trace!("parsing link (type {})", hdr.rtm_type);
+                log::trace!("parsing link (type {hdr_type})");
                 LinkInfo::from_raw(data).map(|opt| opt.map(Self::Link))
             }
             RTM_NEWADDR | RTM_DELADDR => {
-                log::trace!("parsing addr (type {})", hdr.rtm_type);
+                log::trace!("parsing addr (type {hdr_type})");
                 AddressInfo::from_raw(data).map(|o| o.map(Self::Address))
             }
             _ => {
-                log::info!("dropping event of type {}", hdr.rtm_type);
+                log::info!("dropping event of type {hdr_type}");
                 Ok(None)
             }
         }
@@ -0,0 +1,139 @@
+//! IPv6 address states, e.g. whether duplicate address detection (DAD) has
+//! finished.
+//!
+//! Routing messages don't carry these, so they're read with the
+//! `SIOCGIFAFLAG_IN6` ioctl instead, like `ifconfig` does. Parsing messages
+//! doesn't read them, as that would mean an ioctl for every address; it's up
+//! to whoever is interested in an address.
+//!
+//! Linux gives the equivalent `IFA_F_*` flags in netlink messages, which are
+//! out of scope here as only routing sockets are read.
+
+use std::net::Ipv6Addr;
+use std::os::fd::AsRawFd;
+use std::{io, mem};
+
+use nix::libc::{c_char, c_int, c_ulong, ioctl, sockaddr_in6, AF_INET6, IFNAMSIZ};
+use nix::sys::socket::{socket, AddressFamily, SockFlag, SockType};
+
+use crate::addresses::flag_names;
+use crate::header::interface_index_to_name;
+
+/// `_IOWR('i', 73, struct in6_ifreq)`
+const SIOCGIFAFLAG_IN6: c_ulong = 0xc1206949;
+
+const IN6_IFF_ANYCAST: i32 = 0x0001;
+const IN6_IFF_TENTATIVE: i32 = 0x0002;
+const IN6_IFF_DUPLICATED: i32 = 0x0004;
+const IN6_IFF_DETACHED: i32 = 0x0008;
+const IN6_IFF_DEPRECATED: i32 = 0x0010;
+const IN6_IFF_NODAD: i32 = 0x0020;
+const IN6_IFF_AUTOCONF: i32 = 0x0040;
+const IN6_IFF_TEMPORARY: i32 = 0x0080;
+const IN6_IFF_DYNAMIC: i32 = 0x0100;
+const IN6_IFF_OPTIMISTIC: i32 = 0x0200;
+const IN6_IFF_SECURED: i32 = 0x0400;
+
+/// Names for each `IN6_IFF_*` flag, as used in `names()` and serialisation.
+const IN6_FLAG_NAMES: &[(i32, &str)] = &[
+    (IN6_IFF_ANYCAST, "anycast"),
+    (IN6_IFF_TENTATIVE, "tentative"),
+    (IN6_IFF_DUPLICATED, "duplicated"),
+    (IN6_IFF_DETACHED, "detached"),
+    (IN6_IFF_DEPRECATED, "deprecated"),
+    (IN6_IFF_NODAD, "nodad"),
+    (IN6_IFF_AUTOCONF, "autoconf"),
+    (IN6_IFF_TEMPORARY, "temporary"),
+    (IN6_IFF_DYNAMIC, "dynamic"),
+    (IN6_IFF_OPTIMISTIC, "optimistic"),
+    (IN6_IFF_SECURED, "secured"),
+];
+
+/// `struct in6_ifreq`, with the union cut down to the members we use.
+#[repr(C)]
+union In6IfreqData {
+    addr: sockaddr_in6,
+    flags6: c_int,
+    /// The size of the biggest member, `struct icmp6_ifstat`
+    _pad: [u8; 272],
+}
+
+#[repr(C)]
+struct In6Ifreq {
+    name: [c_char; IFNAMSIZ],
+    data: In6IfreqData,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Ipv6AddressFlags(i32);
+
+impl Ipv6AddressFlags {
+    pub fn new(val: i32) -> Self {
+        Self(val)
+    }
+
+    /// Flags for an address which is still going through duplicate address
+    /// detection, e.g. to stand in until the real ones can be read.
+    pub fn tentative() -> Self {
+        Self(IN6_IFF_TENTATIVE)
+    }
+
+    /// Names of all flags which are set, e.g. `["tentative", "autoconf"]`.
+    pub fn names(&self) -> Vec<&'static str> {
+        flag_names(self.0, IN6_FLAG_NAMES)
+    }
+
+    /* duplicate address detection is still running */
+    pub fn is_tentative(&self) -> bool {
+        self.0 & IN6_IFF_TENTATIVE != 0
+    }
+    /* duplicate address detection found another node using it */
+    pub fn is_duplicated(&self) -> bool {
+        self.0 & IN6_IFF_DUPLICATED != 0
+    }
+    /* past its preferred lifetime, so not used for new connections */
+    pub fn is_deprecated(&self) -> bool {
+        self.0 & IN6_IFF_DEPRECATED != 0
+    }
+    /* in use before duplicate address detection has finished (RFC 4429) */
+    pub fn is_optimistic(&self) -> bool {
+        self.0 & IN6_IFF_OPTIMISTIC != 0
+    }
+
+    /// Whether duplicate address detection has finished, and found no one
+    /// else using the address.
+    pub fn has_passed_dad(&self) -> bool {
+        !(self.is_tentative() || self.is_duplicated() || self.is_optimistic())
+    }
+}
+
+/// Reads the flags of `addr`, on the interface with the given index.
+pub fn address_flags(index: u16, addr: Ipv6Addr) -> io::Result<Ipv6AddressFlags> {
+    let name = interface_index_to_name(index.into())
+        .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "no such interface"))?;
+
+    let mut req: In6Ifreq = unsafe { mem::zeroed() };
+    for (dst, src) in req.name.iter_mut().zip(name.bytes().take(IFNAMSIZ - 1)) {
+        *dst = src as c_char;
+    }
+
+    let mut sin6: sockaddr_in6 = unsafe { mem::zeroed() };
+    sin6.sin6_len = mem::size_of::<sockaddr_in6>() as u8;
+    sin6.sin6_family = AF_INET6 as u8;
+    // For link-local addresses, the kernel fills in the scope from the
+    // interface
+    sin6.sin6_addr.s6_addr = addr.octets();
+    req.data.addr = sin6;
+
+    let fd = socket(
+        AddressFamily::Inet6,
+        SockType::Datagram,
+        SockFlag::empty(),
+        None,
+    )?;
+    if unsafe { ioctl(fd.as_raw_fd(), SIOCGIFAFLAG_IN6, &mut req) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    Ok(Ipv6AddressFlags(unsafe { req.data.flags6 }))
+}
@@ -0,0 +1,176 @@
+//! Kernel events from a `PF_SYSTEM` socket, for what routing messages don't
+//! cover: so far, only another host using one of our IPv4 addresses, which
+//! the kernel notices from its ARP traffic.
+
+use std::io::{self, Read};
+use std::net::Ipv4Addr;
+use std::os::fd::AsRawFd;
+use std::os::unix::net::UnixStream;
+
+use nix::libc::{c_ulong, ioctl, IFNAMSIZ};
+use nix::sys::socket::{socket, AddressFamily, SockFlag, SockProtocol, SockType};
+
+/// `_IOW('e', 2, struct kev_request)`
+const SIOCSKEVFILT: c_ulong = 0x800c6502;
+
+const KEV_VENDOR_APPLE: u32 = 1;
+const KEV_NETWORK_CLASS: u32 = 1;
+const KEV_INET_SUBCLASS: u32 = 1;
+const KEV_INET_ARPCOLLISION: u32 = 7;
+
+/// The size of `struct kern_event_msg`, up to its `event_data`
+const KERN_EVENT_MSG_LEN: usize = 24;
+/// The size of `struct net_event_data`, which starts every network event
+const NET_EVENT_DATA_LEN: usize = 8 + IFNAMSIZ;
+
+/// `struct kev_request`, which picks the events a socket gets.
+#[repr(C)]
+struct KevRequest {
+    vendor_code: u32,
+    kev_class: u32,
+    kev_subclass: u32,
+}
+
+/// Another host is using one of our IPv4 addresses.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ArpCollision {
+    /// The interface the address is on, e.g. `en0`
+    pub interface_name: String,
+    pub ip: Ipv4Addr,
+    /// The other host's link-layer address
+    pub link_layer_addr: Vec<u8>,
+}
+
+/// A socket which only gets IPv4 kernel events.
+pub(crate) struct KernelEventSocket {
+    socket: UnixStream,
+    buf: [u8; 512],
+}
+
+impl KernelEventSocket {
+    pub fn new() -> io::Result<Self> {
+        let fd = socket(
+            AddressFamily::System,
+            SockType::Raw,
+            SockFlag::empty(),
+            SockProtocol::KextEvent,
+        )?;
+
+        let mut req = KevRequest {
+            vendor_code: KEV_VENDOR_APPLE,
+            kev_class: KEV_NETWORK_CLASS,
+            kev_subclass: KEV_INET_SUBCLASS,
+        };
+        if unsafe { ioctl(fd.as_raw_fd(), SIOCSKEVFILT, &mut req) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        Ok(Self {
+            socket: fd.into(),
+            buf: [0; 512],
+        })
+    }
+
+    pub fn as_raw_fd(&self) -> i32 {
+        self.socket.as_raw_fd()
+    }
+
+    /// Reads the next event, giving it if it's an ARP collision.
+    pub fn read(&mut self) -> io::Result<Option<ArpCollision>> {
+        let n = self.socket.read(&mut self.buf)?;
+        Ok(parse_collision(&self.buf[..n]))
+    }
+}
+
+/// Parses a `struct kern_event_msg`, if it's a `KEV_INET_ARPCOLLISION`
+/// carrying a `struct kev_in_collision`.
+fn parse_collision(data: &[u8]) -> Option<ArpCollision> {
+    let word = |at: usize| Some(u32::from_ne_bytes(data.get(at..at + 4)?.try_into().ok()?));
+    let kind = (word(4)?, word(8)?, word(12)?, word(20)?);
+    if kind
+        != (
+            KEV_VENDOR_APPLE,
+            KEV_NETWORK_CLASS,
+            KEV_INET_SUBCLASS,
+            KEV_INET_ARPCOLLISION,
+        )
+    {
+        return None;
+    }
+
+    // struct net_event_data: the interface's family, unit and name (without
+    // the unit)
+    let event = data.get(KERN_EVENT_MSG_LEN..)?;
+    let unit = u32::from_ne_bytes(event.get(4..8)?.try_into().ok()?);
+    let name = event.get(8..NET_EVENT_DATA_LEN)?;
+    let name = name.split(|b| *b == 0).next().unwrap_or_default();
+
+    // Then the address, and the other host's hardware type, length and
+    // address
+    let rest = event.get(NET_EVENT_DATA_LEN..)?;
+    let ip: [u8; 4] = rest.get(..4)?.try_into().ok()?;
+    let hw_len = usize::from(*rest.get(5)?);
+    let link_layer_addr = rest.get(6..6 + hw_len)?.to_vec();
+
+    Some(ArpCollision {
+        interface_name: format!("{}{unit}", String::from_utf8_lossy(name)),
+        ip: Ipv4Addr::from(ip),
+        link_layer_addr,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A `KEV_INET_ARPCOLLISION` event for 192.0.2.10 on en0.
+    fn collision_event(event_code: u32) -> Vec<u8> {
+        let mut data = Vec::new();
+        for word in [
+            0,
+            KEV_VENDOR_APPLE,
+            KEV_NETWORK_CLASS,
+            KEV_INET_SUBCLASS,
+            0,
+            event_code,
+        ] {
+            data.extend_from_slice(&word.to_ne_bytes());
+        }
+        // if_family, if_unit, if_name
+        data.extend_from_slice(&2u32.to_ne_bytes());
+        data.extend_from_slice(&0u32.to_ne_bytes());
+        let mut name = [0u8; IFNAMSIZ];
+        name[..2].copy_from_slice(b"en");
+        data.extend_from_slice(&name);
+        // ia_ipaddr, hw_type, hw_len, hw_addr
+        data.extend_from_slice(&[192, 0, 2, 10, 1, 6]);
+        data.extend_from_slice(&[0xaa, 0xbb, 0xcc, 0x00, 0x11, 0x22]);
+        data
+    }
+
+    #[test]
+    fn collisions() {
+        assert_eq!(
+            parse_collision(&collision_event(KEV_INET_ARPCOLLISION)),
+            Some(ArpCollision {
+                interface_name: "en0".to_string(),
+                ip: Ipv4Addr::new(192, 0, 2, 10),
+                link_layer_addr: vec![0xaa, 0xbb, 0xcc, 0x00, 0x11, 0x22],
+            })
+        );
+    }
+
+    #[test]
+    fn other_events() {
+        // KEV_INET_NEW_ADDR
+        assert_eq!(parse_collision(&collision_event(1)), None);
+    }
+
+    #[test]
+    fn truncated_events() {
+        let data = collision_event(KEV_INET_ARPCOLLISION);
+        for len in [0, 12, KERN_EVENT_MSG_LEN + 10, data.len() - 1] {
+            assert_eq!(parse_collision(&data[..len]), None, "{len} bytes");
+        }
+    }
+}
@@ -1,5 +1,12 @@
 pub mod addresses;
 pub mod header;
+pub mod in6;
+pub mod kern_event;
 pub mod link;
+pub mod neighbor;
 pub mod route;
+#[cfg(feature = "serde")]
+mod ser;
 pub mod socket;
+pub mod sysctl;
+pub mod table;
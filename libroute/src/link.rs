@@ -21,9 +21,30 @@ use nix::libc::{
     RTM_NEWMADDR2,
 };
 
-use crate::addresses::{AddressFlags, AddressParseError, AddressSet};
+use crate::addresses::{flag_names, AddressFlags, AddressParseError, AddressSet};
+
+/// Names for each `IFF_*` flag, as used in `names()` and serialisation.
+const LINK_FLAG_NAMES: &[(i32, &str)] = &[
+    (IFF_UP, "up"),
+    (IFF_BROADCAST, "broadcast"),
+    (IFF_DEBUG, "debug"),
+    (IFF_LOOPBACK, "loopback"),
+    (IFF_POINTOPOINT, "pointopoint"),
+    (IFF_NOTRAILERS, "notrailers"),
+    (IFF_RUNNING, "running"),
+    (IFF_NOARP, "noarp"),
+    (IFF_PROMISC, "promisc"),
+    (IFF_ALLMULTI, "allmulti"),
+    (IFF_OACTIVE, "oactive"),
+    (IFF_SIMPLEX, "simplex"),
+];
 
 #[derive(Debug)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize),
+    serde(rename_all = "snake_case")
+)]
 pub enum MessageType {
     Info,
     NewAddr,
@@ -50,6 +71,7 @@ impl MessageType {
 }
 
 #[derive(Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
 pub struct LinkInfo {
     pub operation: MessageType,
     pub index: u16,
@@ -128,6 +150,11 @@ impl LinkFlags {
         Self(flags)
     }
 
+    /// Names of all flags which are set, e.g. `["up", "running"]`.
+    pub fn names(&self) -> Vec<&'static str> {
+        flag_names(self.0, LINK_FLAG_NAMES)
+    }
+
     pub fn is_up(&self) -> bool {
         self.0 & IFF_UP != 0
     }
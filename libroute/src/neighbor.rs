@@ -0,0 +1,221 @@
+//! Neighbour (ARP/NDP) entries.
+//!
+//! These live in the routing table as host routes with `RTF_LLINFO` set,
+//! whose gateway is the link-layer address of the neighbour. This is the BSD
+//! view of them: Linux's RTM_NEWNEIGH messages and NUD states are out of
+//! scope, as libroute only supports routing sockets.
+
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::addresses::SockAddr;
+use crate::header::Header;
+use crate::route::{MessageType, RouteInfo};
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize),
+    serde(rename_all = "snake_case")
+)]
+pub enum NeighborState {
+    /// Resolution is still in progress, or has failed and is being held off
+    /// for a while (`RTF_REJECT`), so we don't have a usable link-layer
+    /// address.
+    Incomplete,
+    /// We have a link-layer address for the neighbour.
+    Resolved,
+}
+
+#[derive(Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct Neighbor {
+    pub index: u16,
+    pub ip: IpAddr,
+    #[cfg_attr(
+        feature = "serde",
+        serde(rename = "mac", serialize_with = "crate::ser::mac")
+    )]
+    pub link_layer_addr: Vec<u8>,
+    pub state: NeighborState,
+    /// When the entry expires, in seconds since the Unix epoch. Permanent
+    /// entries don't.
+    pub expire: Option<u64>,
+}
+
+impl Neighbor {
+    /// Reads a neighbour entry from a route message, if it is one.
+    pub fn from_route(info: &RouteInfo) -> Option<Self> {
+        if !info.flags.is_llinfo() {
+            return None;
+        }
+
+        let ip = info.addrs.destination.as_ref()?.ip()?;
+        let link_layer_addr = match &info.addrs.gateway {
+            Some(SockAddr::Link(l)) => l.link_layer_addr.clone(),
+            _ => return None,
+        };
+
+        let state = match link_layer_addr.is_empty() || info.flags.is_reject() {
+            true => NeighborState::Incomplete,
+            false => NeighborState::Resolved,
+        };
+        // NOTE: The kernel gives this as calendar time, with 0 for never.
+        let expire = u64::try_from(info.metrics.expire).ok().filter(|e| *e != 0);
+
+        Some(Self {
+            index: info.index,
+            ip,
+            link_layer_addr,
+            state,
+            expire,
+        })
+    }
+
+    /// Whether we have a link-layer address for the neighbour which hasn't
+    /// expired. Expired entries stay around until they're next used, when
+    /// they're resolved again.
+    pub fn is_resolved(&self) -> bool {
+        self.state == NeighborState::Resolved && !self.has_expired(SystemTime::now())
+    }
+
+    fn has_expired(&self, now: SystemTime) -> bool {
+        let now = now
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+        self.expire.is_some_and(|expire| expire <= now)
+    }
+}
+
+/// Neighbour entries, kept up to date from route messages.
+#[derive(Debug, Default)]
+pub struct NeighborTable {
+    entries: HashMap<IpAddr, Neighbor>,
+}
+
+impl NeighborTable {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Applies a message to the table. Returns the affected neighbour if the
+    /// message was about one.
+    pub fn update(&mut self, h: &Header) -> Option<&Neighbor> {
+        let info = match h {
+            Header::Route(info) => info,
+            _ => return None,
+        };
+        let neighbor = Neighbor::from_route(info)?;
+        let ip = neighbor.ip;
+
+        match info.operation {
+            MessageType::Delete => {
+                log::debug!("neighbor {ip} removed");
+                self.entries.remove(&ip);
+                None
+            }
+            _ => {
+                log::debug!("neighbor {ip} is {:?}", neighbor.state);
+                self.entries.insert(ip, neighbor);
+                self.entries.get(&ip)
+            }
+        }
+    }
+
+    pub fn get(&self, ip: &IpAddr) -> Option<&Neighbor> {
+        self.entries.get(ip)
+    }
+
+    pub fn is_resolved(&self, ip: &IpAddr) -> bool {
+        self.get(ip).map(Neighbor::is_resolved).unwrap_or(false)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{Ipv4Addr, SocketAddrV4};
+    use std::time::Duration;
+
+    use nix::libc::{RTF_HOST, RTF_LLINFO, RTF_REJECT, RTF_UP};
+
+    use super::*;
+    use crate::addresses::{AddressSet, DataLinkAddr};
+    use crate::route::{RouteMetrics, RoutingFlags};
+
+    fn entry(flags: i32, mac: &[u8], expire: i32) -> RouteInfo {
+        RouteInfo {
+            operation: MessageType::Add,
+            index: 4,
+            flags: RoutingFlags::from_raw(flags),
+            metrics: RouteMetrics {
+                expire,
+                ..Default::default()
+            },
+            addrs: AddressSet {
+                destination: Some(SockAddr::V4(SocketAddrV4::new(
+                    Ipv4Addr::new(192, 168, 1, 1),
+                    0,
+                ))),
+                gateway: Some(SockAddr::Link(DataLinkAddr {
+                    index: 4,
+                    link_layer_addr: mac.to_vec(),
+                    interface_name: String::new(),
+                })),
+                netmask: None,
+                genmask: None,
+                broadcast: None,
+                interface_addr: None,
+                interface_link: None,
+            },
+        }
+    }
+
+    const LLINFO: i32 = RTF_UP | RTF_HOST | RTF_LLINFO;
+    const MAC: [u8; 6] = [0xaa, 0xbb, 0xcc, 0x00, 0x11, 0x22];
+
+    #[test]
+    fn resolved() {
+        let neighbor = Neighbor::from_route(&entry(LLINFO, &MAC, 0)).unwrap();
+        assert_eq!(neighbor.ip, IpAddr::V4(Ipv4Addr::new(192, 168, 1, 1)));
+        assert_eq!(neighbor.link_layer_addr, MAC);
+        assert_eq!(neighbor.state, NeighborState::Resolved);
+        assert_eq!(neighbor.expire, None);
+        assert!(neighbor.is_resolved());
+    }
+
+    #[test]
+    fn incomplete() {
+        let neighbor = Neighbor::from_route(&entry(LLINFO, &[], 0)).unwrap();
+        assert_eq!(neighbor.state, NeighborState::Incomplete);
+        assert!(!neighbor.is_resolved());
+
+        // Resolution failed, so the entry is held off for a while
+        let neighbor = Neighbor::from_route(&entry(LLINFO | RTF_REJECT, &MAC, 0)).unwrap();
+        assert_eq!(neighbor.state, NeighborState::Incomplete);
+        assert!(!neighbor.is_resolved());
+    }
+
+    #[test]
+    fn expired() {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs() as i32;
+
+        let neighbor = Neighbor::from_route(&entry(LLINFO, &MAC, now + 60)).unwrap();
+        assert!(neighbor.is_resolved());
+        assert!(neighbor.has_expired(SystemTime::now() + Duration::from_secs(61)));
+
+        let neighbor = Neighbor::from_route(&entry(LLINFO, &MAC, now - 1)).unwrap();
+        assert_eq!(neighbor.state, NeighborState::Resolved);
+        assert!(!neighbor.is_resolved());
+    }
+
+    #[test]
+    fn not_neighbors() {
+        // e.g. a default route, whose gateway is a link-layer address
+        assert!(Neighbor::from_route(&entry(RTF_UP, &MAC, 0)).is_none());
+    }
+}
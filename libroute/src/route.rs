@@ -1,7 +1,11 @@
 use nix::libc::{
     rt_metrics,
     rt_msghdr,
+    RTF_BLACKHOLE,
     RTF_GATEWAY,
+    RTF_IFSCOPE,
+    RTF_LLINFO,
+    RTF_REJECT,
     RTF_UP,
     RTM_ADD,
     RTM_CHANGE,
@@ -10,9 +14,14 @@ use nix::libc::{
     RTM_GET2,
 };
 
-use crate::addresses::{AddressFlags, AddressParseError, AddressSet};
+use crate::addresses::{flag_names, AddressFlags, AddressParseError, AddressSet, ROUTE_FLAG_NAMES};
 
 #[derive(Clone, Debug)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize),
+    serde(rename_all = "snake_case")
+)]
 /// Type of message from kernel
 /// Comments taken from source code
 /// https://opensource.apple.com/source/network_cmds/network_cmds-606.40.2/route.tproj/route.c.auto.html
@@ -45,6 +54,7 @@ impl MessageType {
 }
 
 #[derive(Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
 pub struct RouteInfo {
     pub operation: MessageType,
     pub index: u16,
@@ -81,6 +91,13 @@ impl RouteInfo {
         // confirm this.
         let hdr = unsafe { *hdr_ptr };
 
+        // Replies to our own requests which failed (e.g. looking up a route
+        // which doesn't exist) say nothing about the routing table.
+        if hdr.rtm_errno != 0 {
+            log::debug!("dropping reply to failed request (errno {})", hdr.rtm_errno);
+            return Ok(None);
+        }
+
         // Validate the message type
         let op = match (hdr).rtm_type as i32 {
             RTM_ADD => MessageType::Add,
@@ -124,6 +141,11 @@ impl RoutingFlags {
         Self(flags)
     }
 
+    /// Names of all flags which are set, e.g. `["up", "gateway"]`.
+    pub fn names(&self) -> Vec<&'static str> {
+        flag_names(self.0, ROUTE_FLAG_NAMES)
+    }
+
     // TODO: We may want to support more here?
     pub fn is_up(&self) -> bool {
         self.0 & (RTF_UP) != 0
@@ -132,9 +154,26 @@ impl RoutingFlags {
     pub fn has_gateway(&self) -> bool {
         self.0 & (RTF_GATEWAY) != 0
     }
+
+    /// Set on neighbour (ARP/NDP) entries
+    pub fn is_llinfo(&self) -> bool {
+        self.0 & (RTF_LLINFO) != 0
+    }
+
+    /// Set on routes which only apply to traffic bound to their interface,
+    /// such as the default routes of interfaces other than the primary one
+    pub fn is_ifscope(&self) -> bool {
+        self.0 & (RTF_IFSCOPE) != 0
+    }
+
+    /// Whether traffic matching the route is refused or silently dropped
+    pub fn is_reject(&self) -> bool {
+        self.0 & (RTF_REJECT | RTF_BLACKHOLE) != 0
+    }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
 pub struct RouteMetrics {
     pub mtu: u64,
     pub hopcount: u32,
@@ -0,0 +1,192 @@
+//! Serde representations for types which can't simply be derived.
+//!
+//! Flags are serialised as lists of the names of the flags which are set,
+//! and link-layer addresses as lower-case colon-separated hex.
+
+use std::net::SocketAddr;
+
+use serde::ser::{SerializeMap, SerializeSeq};
+use serde::{Serialize, Serializer};
+
+use crate::addresses::{format_mac, AddressInfoFlags, SockAddr};
+use crate::in6::Ipv6AddressFlags;
+use crate::link::LinkFlags;
+use crate::route::RoutingFlags;
+
+fn names<S: Serializer>(names: &[&str], s: S) -> Result<S::Ok, S::Error> {
+    let mut seq = s.serialize_seq(Some(names.len()))?;
+    for name in names {
+        seq.serialize_element(name)?;
+    }
+    seq.end()
+}
+
+impl Serialize for AddressInfoFlags {
+    fn serialize<S: Serializer>(&self, s: S) -> Result<S::Ok, S::Error> {
+        names(&self.names(), s)
+    }
+}
+
+impl Serialize for Ipv6AddressFlags {
+    fn serialize<S: Serializer>(&self, s: S) -> Result<S::Ok, S::Error> {
+        names(&self.names(), s)
+    }
+}
+
+impl Serialize for RoutingFlags {
+    fn serialize<S: Serializer>(&self, s: S) -> Result<S::Ok, S::Error> {
+        names(&self.names(), s)
+    }
+}
+
+impl Serialize for LinkFlags {
+    fn serialize<S: Serializer>(&self, s: S) -> Result<S::Ok, S::Error> {
+        names(&self.names(), s)
+    }
+}
+
+impl Serialize for SockAddr {
+    fn serialize<S: Serializer>(&self, s: S) -> Result<S::Ok, S::Error> {
+        match self {
+            Self::V4(a) => {
+                let mut map = s.serialize_map(Some(2))?;
+                map.serialize_entry("family", "inet")?;
+                map.serialize_entry("address", a.ip())?;
+                map.end()
+            }
+            Self::V6(a) => {
+                let mut map = s.serialize_map(Some(3))?;
+                map.serialize_entry("family", "inet6")?;
+                map.serialize_entry("address", a.ip())?;
+                map.serialize_entry("scope_id", &a.scope_id())?;
+                map.end()
+            }
+            Self::Link(l) => {
+                let mut map = s.serialize_map(Some(4))?;
+                map.serialize_entry("family", "link")?;
+                map.serialize_entry("index", &l.index)?;
+                map.serialize_entry("name", &l.interface_name)?;
+                map.serialize_entry("mac", &optional_mac(&l.link_layer_addr))?;
+                map.end()
+            }
+        }
+    }
+}
+
+/// Links without a link-layer address get `null` rather than `""`.
+fn optional_mac(addr: &[u8]) -> Option<String> {
+    (!addr.is_empty()).then(|| format_mac(addr))
+}
+
+pub(crate) fn mac<S: Serializer>(addr: &[u8], s: S) -> Result<S::Ok, S::Error> {
+    optional_mac(addr).serialize(s)
+}
+
+pub(crate) fn socket_ip<S: Serializer>(addr: &Option<SocketAddr>, s: S) -> Result<S::Ok, S::Error> {
+    addr.map(|a| a.ip()).serialize(s)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{Ipv4Addr, SocketAddrV4};
+
+    use nix::libc::{IFF_RUNNING, IFF_UP, RTF_GATEWAY, RTF_UP};
+    use serde_json::{json, Value};
+
+    use super::*;
+    use crate::addresses::{AddressSet, DataLinkAddr};
+    use crate::header::Header;
+    use crate::link::{LinkInfo, MessageType as LinkMessageType};
+    use crate::route::{MessageType, RouteInfo, RouteMetrics};
+
+    fn addresses(destination: Option<SockAddr>, gateway: Option<SockAddr>) -> AddressSet {
+        AddressSet {
+            destination,
+            gateway,
+            netmask: None,
+            genmask: None,
+            broadcast: None,
+            interface_addr: None,
+            interface_link: None,
+        }
+    }
+
+    fn link(mac: Vec<u8>) -> DataLinkAddr {
+        DataLinkAddr {
+            index: 4,
+            link_layer_addr: mac,
+            interface_name: "en0".to_string(),
+        }
+    }
+
+    fn to_json(header: &Header) -> Value {
+        serde_json::to_value(header).unwrap()
+    }
+
+    #[test]
+    fn routes() {
+        let route = Header::Route(RouteInfo {
+            operation: MessageType::Get,
+            index: 4,
+            flags: RoutingFlags::from_raw(RTF_UP | RTF_GATEWAY),
+            metrics: RouteMetrics::default(),
+            addrs: addresses(
+                Some(SockAddr::V4(SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, 0))),
+                Some(SockAddr::Link(link(vec![
+                    0xAA, 0xBB, 0xCC, 0x0D, 0x0E, 0x0F,
+                ]))),
+            ),
+        });
+
+        let json = to_json(&route);
+        assert_eq!(json["kind"], "route");
+        assert_eq!(json["operation"], "get");
+        assert_eq!(json["flags"], json!(["up", "gateway"]));
+        assert_eq!(
+            json["addrs"]["destination"],
+            json!({"family": "inet", "address": "0.0.0.0"})
+        );
+        assert_eq!(
+            json["addrs"]["gateway"],
+            json!({"family": "link", "index": 4, "name": "en0", "mac": "aa:bb:cc:0d:0e:0f"})
+        );
+        assert_eq!(json["addrs"]["netmask"], Value::Null);
+    }
+
+    #[test]
+    fn links() {
+        let mut addrs = addresses(None, None);
+        addrs.interface_link = Some(link(vec![0xAA, 0xBB, 0xCC, 0x0D, 0x0E, 0x0F]));
+        let info = Header::Link(LinkInfo {
+            operation: LinkMessageType::Info,
+            index: 4,
+            flags: LinkFlags::new(IFF_UP | IFF_RUNNING),
+            addrs,
+        });
+
+        let json = to_json(&info);
+        assert_eq!(json["kind"], "link");
+        assert_eq!(json["operation"], "info");
+        assert_eq!(json["flags"], json!(["up", "running"]));
+        assert_eq!(
+            json["addrs"]["interface_link"],
+            json!({"index": 4, "mac": "aa:bb:cc:0d:0e:0f", "name": "en0"})
+        );
+    }
+
+    #[test]
+    fn links_without_a_mac() {
+        assert_eq!(
+            serde_json::to_value(link(Vec::new())).unwrap(),
+            json!({"index": 4, "mac": null, "name": "en0"})
+        );
+        let addr = SockAddr::Link(link(Vec::new()));
+        assert_eq!(serde_json::to_value(addr).unwrap()["mac"], Value::Null);
+    }
+
+    #[test]
+    fn no_flags() {
+        let flags = RoutingFlags::from_raw(0);
+        assert_eq!(serde_json::to_value(flags).unwrap(), json!([]));
+    }
+}
@@ -1,27 +1,31 @@
+use std::collections::VecDeque;
+use std::fs::File;
 use std::io::{self, Read, Write};
 use std::mem::size_of;
+use std::net::IpAddr;
 use std::os::fd::AsRawFd;
 use std::os::unix::net::UnixStream;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
 
 use nix::libc::{
+    in6_addr,
     in_addr,
     rt_metrics,
     rt_msghdr,
-    sockaddr_dl,
     sockaddr_in,
+    sockaddr_in6,
     uintptr_t,
     AF_INET,
+    AF_INET6,
+    ESRCH,
     RTA_DST,
     RTA_IFA,
     RTA_IFP,
-    RTA_NETMASK,
-    RTF_GATEWAY,
     RTF_HOST,
-    RTF_IFSCOPE,
     RTF_UP,
     RTM_GET,
     RTM_VERSION,
-    RTV_HOPCOUNT,
 };
 use nix::net::if_::if_nametoindex;
 use nix::sys::event::{EventFilter, EventFlag, FilterFlag, KEvent, Kqueue};
@@ -29,125 +33,111 @@ use nix::sys::socket::{self as nix_socket, AddressFamily, SockFlag, SockType};
 
 use crate::addresses::AddressParseError;
 use crate::header::Header;
+use crate::kern_event::{ArpCollision, KernelEventSocket};
+use crate::route::RouteInfo;
 
 const KEVENT_TIMEOUT_ID: uintptr_t = 61;
+/// Timers from `start_timer()` use this plus their id
+const KEVENT_TIMER_BASE_ID: uintptr_t = 62;
 
-const ADDR_LEN: usize = size_of::<sockaddr_dl>();
 const HDR_LEN: usize = size_of::<rt_msghdr>();
-const INT_REQ_SIZE: usize = ADDR_LEN + HDR_LEN;
-
-#[allow(non_camel_case_types)]
-#[derive(Debug, Copy, Clone)]
-#[repr(C)]
-struct route_request {
-    pub rtm: rt_msghdr,
-    pub dst: sockaddr_in,
-    pub mask: sockaddr_in,
+
+fn empty_metrics() -> rt_metrics {
+    rt_metrics {
+        rmx_expire: 0,
+        rmx_locks: 0,
+        rmx_mtu: 0,
+        rmx_hopcount: 0,
+        rmx_recvpipe: 0,
+        rmx_sendpipe: 0,
+        rmx_ssthresh: 0,
+        rmx_rtt: 0,
+        rmx_rttvar: 0,
+        rmx_pksent: 0,
+        rmx_state: 0,
+        rmx_filler: [0u32; 3],
+    }
 }
 
-fn interface_info_req(if_idx: u16, seq: i32) -> [u8; INT_REQ_SIZE] {
+/// Asks which route the kernel would use to reach `dst`, like `route get`.
+fn route_lookup_req(dst: IpAddr, seq: i32) -> Vec<u8> {
+    let addr_len = match dst {
+        IpAddr::V4(_) => size_of::<sockaddr_in>(),
+        IpAddr::V6(_) => size_of::<sockaddr_in6>(),
+    };
+
     let hdr = rt_msghdr {
-        rtm_msglen: INT_REQ_SIZE as u16,
+        rtm_msglen: (HDR_LEN + addr_len) as u16,
         rtm_version: RTM_VERSION as u8,
         rtm_type: RTM_GET as u8,
-        rtm_index: if_idx,
-        // Required to scope the request down to just the index specifiecd
-        // in rtm_index
-        rtm_flags: RTF_IFSCOPE | RTF_HOST,
+        rtm_index: 0,
+        rtm_flags: RTF_UP | RTF_HOST,
+        // Also ask for the interface, so we know where it'd go
         rtm_addrs: RTA_DST | RTA_IFP | RTA_IFA,
         rtm_pid: 0,
         rtm_seq: seq,
         rtm_errno: 0,
         rtm_use: 0,
         rtm_inits: 0,
-        // rtm_inits: RTV_HOPCOUNT,
-        rtm_rmx: rt_metrics {
-            rmx_expire: 0,
-            rmx_locks: 0,
-            rmx_mtu: 0,
-            rmx_hopcount: 0,
-            rmx_recvpipe: 0,
-            rmx_sendpipe: 0,
-            rmx_ssthresh: 0,
-            rmx_rtt: 0,
-            rmx_rttvar: 0,
-            rmx_pksent: 0,
-            rmx_state: 0,
-            rmx_filler: [0u32; 3],
-        },
-    };
-    let sockaddr = sockaddr_dl {
-        sdl_len: ADDR_LEN as u8,
-        sdl_family: AF_INET as u8,
-        sdl_index: if_idx,
-        sdl_type: 0,
-        sdl_nlen: 0,
-        sdl_alen: 0,
-        sdl_slen: 0,
-        sdl_data: [0i8; 12],
+        rtm_rmx: empty_metrics(),
     };
 
-    let mut buf = [0u8; INT_REQ_SIZE];
-    let (hdr_slice, addr_slice) = unsafe {
+    let mut buf = vec![0u8; HDR_LEN + addr_len];
+    unsafe {
         let hdr_ptr = (&hdr) as *const _ as *const u8;
-        let hdr_slice = std::slice::from_raw_parts(hdr_ptr, HDR_LEN);
-
-        let addr_ptr = (&sockaddr) as *const _ as *const u8;
-        let addr_slice = std::slice::from_raw_parts(addr_ptr, ADDR_LEN);
-
-        (hdr_slice, addr_slice)
-    };
+        buf[..HDR_LEN].copy_from_slice(std::slice::from_raw_parts(hdr_ptr, HDR_LEN));
+    }
 
-    buf[..HDR_LEN].copy_from_slice(hdr_slice);
-    buf[HDR_LEN..].copy_from_slice(addr_slice);
+    match dst {
+        IpAddr::V4(ip) => {
+            let addr = sockaddr_in {
+                sin_len: addr_len as u8,
+                sin_family: AF_INET as u8,
+                sin_port: 0,
+                sin_addr: in_addr {
+                    s_addr: u32::from_ne_bytes(ip.octets()),
+                },
+                sin_zero: [0; 8],
+            };
+            unsafe {
+                let addr_ptr = (&addr) as *const _ as *const u8;
+                buf[HDR_LEN..].copy_from_slice(std::slice::from_raw_parts(addr_ptr, addr_len));
+            }
+        }
+        IpAddr::V6(ip) => {
+            let addr = sockaddr_in6 {
+                sin6_len: addr_len as u8,
+                sin6_family: AF_INET6 as u8,
+                sin6_port: 0,
+                sin6_flowinfo: 0,
+                sin6_addr: in6_addr {
+                    s6_addr: ip.octets(),
+                },
+                sin6_scope_id: 0,
+            };
+            unsafe {
+                let addr_ptr = (&addr) as *const _ as *const u8;
+                buf[HDR_LEN..].copy_from_slice(std::slice::from_raw_parts(addr_ptr, addr_len));
+            }
+        }
+    }
 
     buf
 }
 
-fn default_ipv4_request(seq: i32) -> route_request {
-    let raw_addr_4 = in_addr { s_addr: 0 };
-    route_request {
-        rtm: rt_msghdr {
-            rtm_msglen: size_of::<route_request>() as u16,
-            rtm_version: RTM_VERSION as u8,
-            rtm_type: RTM_GET as u8,
-            rtm_index: 0,
-            rtm_flags: RTF_UP | RTF_GATEWAY,
-            rtm_addrs: RTA_DST | RTA_NETMASK,
-            rtm_pid: 0,
-            rtm_seq: seq,
-            rtm_errno: 0,
-            rtm_use: 0,
-            rtm_inits: RTV_HOPCOUNT as u32,
-            rtm_rmx: rt_metrics {
-                rmx_expire: 0,
-                rmx_locks: 0,
-                rmx_mtu: 0,
-                rmx_hopcount: 0,
-                rmx_recvpipe: 0,
-                rmx_sendpipe: 0,
-                rmx_ssthresh: 0,
-                rmx_rtt: 0,
-                rmx_rttvar: 0,
-                rmx_pksent: 0,
-                rmx_state: 0,
-                rmx_filler: [0u32; 3],
-            },
-        },
-        dst: sockaddr_in {
-            sin_len: size_of::<sockaddr_in>() as u8,
-            sin_family: AF_INET as u8,
-            sin_port: 0,
-            sin_addr: raw_addr_4,
-            sin_zero: [0; 8],
-        },
-        mask: sockaddr_in {
-            sin_len: size_of::<sockaddr_in>() as u8,
-            sin_family: AF_INET as u8,
-            sin_port: 0,
-            sin_addr: raw_addr_4,
-            sin_zero: [0; 8],
-        },
+/// Gives the error number of a message if it is the reply to our request
+/// with sequence number `seq`.
+fn reply_errno(data: &[u8], seq: i32) -> Option<i32> {
+    if data.len() < HDR_LEN || data[3] as i32 != RTM_GET {
+        return None;
+    }
+
+    // SAFETY: We've checked this is a route message, which always starts
+    // with a full rt_msghdr.
+    let hdr = unsafe { std::ptr::read_unaligned(data.as_ptr() as *const rt_msghdr) };
+    match hdr.rtm_pid as u32 == std::process::id() && hdr.rtm_seq == seq {
+        true => Some(hdr.rtm_errno),
+        false => None,
     }
 }
 
@@ -171,12 +161,100 @@ impl From<io::Error> for ReadError {
     }
 }
 
+#[derive(thiserror::Error, Debug)]
+pub enum LookupError {
+    #[error("no route to {0}")]
+    NoRoute(IpAddr),
+    #[error("timed out waiting for the route")]
+    Timeout,
+    #[error("IO error: {0}")]
+    IO(#[from] io::Error),
+
+    #[error("error parsing addresses: {0}")]
+    ParsingAddress(#[from] AddressParseError),
+}
+
+impl From<ReadError> for LookupError {
+    fn from(value: ReadError) -> Self {
+        match value {
+            ReadError::Timeout => Self::Timeout,
+            ReadError::IO(e) => Self::IO(e),
+            ReadError::ParsingAddress(e) => Self::ParsingAddress(e),
+        }
+    }
+}
+
 #[derive(Debug, thiserror::Error)]
 pub enum RouteSocketCreateError {
     #[error("error creating kqueue: {0}")]
     CreatingKqueue(io::Error),
     #[error("error creating pf_route socket: {0})")]
     CreatingSocket(io::Error),
+    #[error("error registering events with kqueue: {0}")]
+    RegisteringEvents(io::Error),
+}
+
+/// Something which woke us up in `recv_event()`.
+// Only ever handed straight back to the caller, so its size doesn't matter.
+#[allow(clippy::large_enum_variant)]
+#[derive(Debug)]
+pub enum Event {
+    Message(Header),
+    /// The timer with this id, set with `start_timer()`, went off
+    Timer(usize),
+    /// A path being watched with `watch_path()` changed
+    PathChanged(PathBuf),
+    /// Another host is using one of our IPv4 addresses, once
+    /// `watch_arp_collisions()` has been called
+    ArpCollision(ArpCollision),
+}
+
+/// A path being watched, along with the files we have open to watch it.
+struct Watch {
+    path: PathBuf,
+    files: Vec<File>,
+}
+
+impl Watch {
+    /// Opens the path itself (if it exists) and its parent directory, so we
+    /// hear about it being written, replaced, created or deleted.
+    fn open(path: &Path) -> Self {
+        let target = path.canonicalize().unwrap_or_else(|_| path.to_path_buf());
+        let files = [Some(target.as_path()), target.parent()]
+            .into_iter()
+            .flatten()
+            .filter_map(|p| File::open(p).ok())
+            .collect();
+
+        Self {
+            path: path.to_path_buf(),
+            files,
+        }
+    }
+
+    fn events(&self, flags: EventFlag) -> Vec<KEvent> {
+        self.files
+            .iter()
+            .map(|f| {
+                KEvent::new(
+                    f.as_raw_fd() as uintptr_t,
+                    EventFilter::EVFILT_VNODE,
+                    flags,
+                    FilterFlag::NOTE_WRITE
+                        | FilterFlag::NOTE_EXTEND
+                        | FilterFlag::NOTE_ATTRIB
+                        | FilterFlag::NOTE_DELETE
+                        | FilterFlag::NOTE_RENAME,
+                    0,
+                    0,
+                )
+            })
+            .collect()
+    }
+
+    fn has_fd(&self, fd: uintptr_t) -> bool {
+        self.files.iter().any(|f| f.as_raw_fd() as uintptr_t == fd)
+    }
 }
 
 // #[derive(thiserror::Error, Debug)]
@@ -195,8 +273,12 @@ pub struct RouteSocket {
     seq: i32,
     buf: [u8; 2048],
     kqueue: Kqueue,
-    events: Vec<KEvent>,
     event_buf: Vec<KEvent>,
+    /// Events which have happened but not been handed out yet, e.g. messages
+    /// read while waiting for a reply.
+    pending: VecDeque<Event>,
+    watches: Vec<Watch>,
+    kernel_events: Option<KernelEventSocket>,
 
     raw_socket_fd: usize,
     socket: UnixStream,
@@ -240,69 +322,250 @@ impl RouteSocket {
 
         events.push(read_event);
 
+        // NOTE: These are only registered once. Adding the timeout timer
+        // again would restart it.
+        kqueue
+            .kevent(&events, &mut [], None)
+            .map_err(|e| RouteSocketCreateError::RegisteringEvents(e.into()))?;
+
+        let placeholder = KEvent::new(
+            0,
+            EventFilter::EVFILT_READ,
+            EventFlag::empty(),
+            FilterFlag::empty(),
+            0,
+            0,
+        );
+
         Ok(Self {
             seq: 0,
             buf: [0; 2048],
 
             kqueue,
-            event_buf: events.clone(),
-            events,
+            event_buf: vec![placeholder; 8],
+            pending: VecDeque::new(),
+            watches: Vec::new(),
+            kernel_events: None,
 
             socket: socket.into(),
             raw_socket_fd,
         })
     }
 
+    /// Waits for the next message, ignoring timers and watched paths.
     pub fn recv(&mut self) -> Result<Header, ReadError> {
         loop {
-            let res = self
-                .kqueue
-                .kevent(&self.events, &mut self.event_buf, None)?;
-
-            match res {
-                1..=2 => {
-                    let event = self.event_buf.first().expect("i hope we've populated this");
-                    match event.ident() {
-                        KEVENT_TIMEOUT_ID => return Err(ReadError::Timeout),
-                        id if id == self.raw_socket_fd => {
-                            let n = self.socket.read(&mut self.buf)?;
-                            log::trace!("read {n} bytes w kevent");
-
-                            match Header::from_raw(&self.buf[..n])? {
-                                Some(res) => return Ok(res),
-                                None => continue,
-                            };
-                        }
-                        n => panic!("unknown event from kevent {n}"),
-                    }
+            if let Event::Message(h) = self.recv_event()? {
+                return Ok(h);
+            }
+        }
+    }
+
+    /// Waits for the next message, timer or change to a watched path, and
+    /// hands them out in the order they happened.
+    pub fn recv_event(&mut self) -> Result<Event, ReadError> {
+        loop {
+            if let Some(event) = self.pending.pop_front() {
+                return Ok(event);
+            }
+
+            if self.wait()? {
+                let n = self.socket.read(&mut self.buf)?;
+                log::trace!("read {n} bytes w kevent");
+
+                if let Some(h) = Header::from_raw(&self.buf[..n])? {
+                    self.pending.push_back(Event::Message(h));
                 }
-                n @ 3.. => panic!("somehow we got more elements from kevent than we put in? {n}"),
-                n @ ..=0 => panic!("we got {n} elements from kevent?"),
-            };
+            }
         }
     }
-    pub fn request_default_ipv4(&mut self) -> io::Result<()> {
-        let request = default_ipv4_request(self.get_seq());
 
-        log::trace!("req: {:?}", request);
-        let request_bytes: &[u8] = unsafe {
-            let req_ptr = (&request) as *const _ as *const u8;
-            std::slice::from_raw_parts(req_ptr, size_of::<route_request>())
-        };
+    /// Waits for something to happen, queueing up timers, changes to watched
+    /// paths and kernel events. Returns whether the socket can be read from, or
+    /// `ReadError::Timeout` once the overall timeout goes off.
+    fn wait(&mut self) -> Result<bool, ReadError> {
+        let n = self.kqueue.kevent(&[], &mut self.event_buf, None)?;
+
+        let mut readable = false;
+        let mut kernel_event = false;
+        let mut changed = Vec::new();
+        for event in &self.event_buf[..n] {
+            match (event.filter()?, event.ident()) {
+                (EventFilter::EVFILT_TIMER, KEVENT_TIMEOUT_ID) => return Err(ReadError::Timeout),
+                (EventFilter::EVFILT_TIMER, id) => {
+                    let id = id - KEVENT_TIMER_BASE_ID;
+                    self.pending.push_back(Event::Timer(id));
+                }
+                (EventFilter::EVFILT_VNODE, fd) => changed.push(fd),
+                (_, id) if id == self.raw_socket_fd => readable = true,
+                (_, id) if self.is_kernel_event_fd(id) => kernel_event = true,
+                (filter, id) => log::debug!("ignoring unknown {filter:?} event {id}"),
+            }
+        }
+
+        if let (true, Some(events)) = (kernel_event, self.kernel_events.as_mut()) {
+            if let Some(collision) = events.read()? {
+                self.pending.push_back(Event::ArpCollision(collision));
+            }
+        }
+
+        // NOTE: Work out which watches changed before re-opening any of
+        // them, as their new files may reuse the old descriptors.
+        let mut changed_paths: Vec<PathBuf> = Vec::new();
+        for w in &self.watches {
+            if changed.iter().any(|fd| w.has_fd(*fd)) && !changed_paths.contains(&w.path) {
+                changed_paths.push(w.path.clone());
+            }
+        }
+        for path in changed_paths {
+            self.rewatch(&path)?;
+        }
+
+        Ok(readable)
+    }
 
-        log::debug!("sending v4");
-        self.send(request_bytes)?;
+    /// Sets timer `id` to go off after `after`, replacing it if it's already
+    /// set. It fires once, through `recv_event()`.
+    pub fn start_timer(&mut self, id: usize, after: Duration) -> io::Result<()> {
+        let event = KEvent::new(
+            KEVENT_TIMER_BASE_ID + id,
+            EventFilter::EVFILT_TIMER,
+            EventFlag::EV_ONESHOT | EventFlag::EV_ADD | EventFlag::EV_ENABLE,
+            // Milliseconds, without any NOTE_*SECONDS flags
+            FilterFlag::empty(),
+            after.as_millis() as isize,
+            0,
+        );
+
+        log::debug!("starting timer {id} for {after:?}");
+        self.pending
+            .retain(|e| !matches!(e, Event::Timer(t) if *t == id));
+        self.kqueue.kevent(&[event], &mut [], None)?;
         Ok(())
     }
 
-    pub fn request_interface_info(&mut self, if_idx: u16) -> io::Result<()> {
-        let req = interface_info_req(if_idx, self.get_seq());
+    /// Stops timer `id`, if it's still going.
+    pub fn cancel_timer(&mut self, id: usize) -> io::Result<()> {
+        let event = KEvent::new(
+            KEVENT_TIMER_BASE_ID + id,
+            EventFilter::EVFILT_TIMER,
+            EventFlag::EV_DELETE,
+            FilterFlag::empty(),
+            0,
+            0,
+        );
+
+        log::debug!("cancelling timer {id}");
+        self.pending
+            .retain(|e| !matches!(e, Event::Timer(t) if *t == id));
+        match self.kqueue.kevent(&[event], &mut [], None) {
+            // It already went off
+            Err(nix::errno::Errno::ENOENT) => Ok(()),
+            res => res.map(|_| ()).map_err(io::Error::from),
+        }
+    }
+
+    /// Watches a file for changes, which arrive as `Event::PathChanged`
+    /// through `recv_event()`. The file doesn't need to exist yet, and may
+    /// be replaced rather than written to.
+    pub fn watch_path(&mut self, path: &Path) -> io::Result<()> {
+        let watch = Watch::open(path);
+        log::debug!("watching {path:?} ({} files)", watch.files.len());
+        self.kqueue.kevent(
+            &watch.events(EventFlag::EV_ADD | EventFlag::EV_CLEAR),
+            &mut [],
+            None,
+        )?;
+        self.watches.push(watch);
+        Ok(())
+    }
 
-        log::debug!("sending if for idx {if_idx}");
+    /// Listens for other hosts using our IPv4 addresses, which arrive as
+    /// `Event::ArpCollision` through `recv_event()`. Routing messages don't
+    /// say, so these come from kernel events instead.
+    pub fn watch_arp_collisions(&mut self) -> io::Result<()> {
+        let events = KernelEventSocket::new()?;
+        let read_event = KEvent::new(
+            events.as_raw_fd() as uintptr_t,
+            EventFilter::EVFILT_READ,
+            EventFlag::EV_ADD | EventFlag::EV_ENABLE,
+            FilterFlag::empty(),
+            0,
+            0,
+        );
+        self.kqueue.kevent(&[read_event], &mut [], None)?;
+        self.kernel_events = Some(events);
+        Ok(())
+    }
+
+    fn is_kernel_event_fd(&self, fd: uintptr_t) -> bool {
+        self.kernel_events
+            .as_ref()
+            .is_some_and(|k| k.as_raw_fd() as uintptr_t == fd)
+    }
+
+    /// Re-opens a changed watch, since its file may have been replaced, and
+    /// queues up an event for it.
+    fn rewatch(&mut self, path: &Path) -> io::Result<()> {
+        // Closing the old files removes their events
+        self.watches.retain(|w| w.path != path);
+        self.watch_path(path)?;
+        self.pending
+            .push_back(Event::PathChanged(path.to_path_buf()));
+        Ok(())
+    }
+
+    /// Asks the kernel for the route it would use to reach `dst`. The answer
+    /// arrives as a `Get` route message through `recv()`.
+    pub fn request_route(&mut self, dst: IpAddr) -> io::Result<()> {
+        let req = route_lookup_req(dst, self.get_seq());
+
+        log::debug!("sending route lookup for {dst}");
         self.send(&req)?;
         Ok(())
     }
 
+    /// Asks the kernel which route, gateway, interface and source address it
+    /// would use to reach `dst` (like `route get`), and waits for the answer.
+    pub fn lookup_route(&mut self, dst: IpAddr) -> Result<RouteInfo, LookupError> {
+        let seq = self.get_seq();
+        let req = route_lookup_req(dst, seq);
+
+        log::debug!("looking up route for {dst}");
+        if let Err(e) = self.socket.write_all(&req) {
+            return Err(match e.raw_os_error() {
+                Some(ESRCH) => LookupError::NoRoute(dst),
+                _ => e.into(),
+            });
+        }
+
+        // Everyone on a routing socket sees every message, so we may have to
+        // read past other events to find our reply. Waiting through kqueue
+        // means the timeout still applies.
+        loop {
+            if !self.wait()? {
+                continue;
+            }
+            let n = self.socket.read(&mut self.buf)?;
+            let data = &self.buf[..n];
+
+            let errno = match reply_errno(data, seq) {
+                Some(errno) => errno,
+                None => {
+                    if let Some(h) = Header::from_raw(data)? {
+                        self.pending.push_back(Event::Message(h));
+                    }
+                    continue;
+                }
+            };
+
+            return match Header::from_raw(data)? {
+                Some(Header::Route(info)) if errno == 0 => Ok(info),
+                _ => Err(LookupError::NoRoute(dst)),
+            };
+        }
+    }
+
     fn get_seq(&mut self) -> i32 {
         self.seq += 1;
         self.seq
@@ -0,0 +1,96 @@
+//! Snapshots of kernel tables, read through `sysctl(3)`.
+//!
+//! These come back as the same messages we'd receive on a routing socket, so
+//! they can be used to fill in state from before the socket was opened.
+
+use std::mem::size_of;
+use std::{io, ptr};
+
+use nix::libc::{c_int, c_void, sysctl, CTL_NET, ENOMEM, NET_RT_DUMP, NET_RT_IFLIST, PF_ROUTE};
+
+use crate::header::Header;
+use crate::socket::ReadError;
+
+/// Fetches the whole result of a sysctl, retrying if it grew between asking
+/// for its size and reading it.
+fn read_sysctl(mib: &mut [c_int]) -> io::Result<Vec<u8>> {
+    loop {
+        let mut len = 0;
+        let res = unsafe {
+            sysctl(
+                mib.as_mut_ptr(),
+                mib.len() as u32,
+                ptr::null_mut(),
+                &mut len,
+                ptr::null_mut(),
+                0,
+            )
+        };
+        if res < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        let mut buf = vec![0u8; len];
+        let res = unsafe {
+            sysctl(
+                mib.as_mut_ptr(),
+                mib.len() as u32,
+                buf.as_mut_ptr() as *mut c_void,
+                &mut len,
+                ptr::null_mut(),
+                0,
+            )
+        };
+        if res < 0 {
+            let e = io::Error::last_os_error();
+            if e.raw_os_error() == Some(ENOMEM) {
+                log::debug!("table grew while reading, retrying");
+                continue;
+            }
+            return Err(e);
+        }
+
+        buf.truncate(len);
+        return Ok(buf);
+    }
+}
+
+/// Splits a buffer of back-to-back routing messages up, and parses each.
+fn parse_messages(buf: &[u8]) -> Result<Vec<Header>, ReadError> {
+    let mut headers = Vec::new();
+    let mut offset = 0;
+
+    // Every message type starts with a u16 length
+    while offset + size_of::<u16>() <= buf.len() {
+        let len = u16::from_ne_bytes([buf[offset], buf[offset + 1]]) as usize;
+        if len == 0 || offset + len > buf.len() {
+            log::warn!("bad message length {len} at offset {offset}, stopping");
+            break;
+        }
+
+        if let Some(h) = Header::from_raw(&buf[offset..offset + len])? {
+            headers.push(h);
+        }
+        offset += len;
+    }
+
+    Ok(headers)
+}
+
+/// Dumps the routing table for all address families. This includes
+/// neighbour (ARP/NDP) entries.
+pub fn dump_routes() -> Result<Vec<Header>, ReadError> {
+    let mut mib = [CTL_NET, PF_ROUTE, 0, 0, NET_RT_DUMP, 0];
+    let buf = read_sysctl(&mut mib)?;
+    log::debug!("read {} bytes of routes", buf.len());
+    parse_messages(&buf)
+}
+
+/// Lists all interfaces and their addresses, as a `Link` message for each
+/// interface followed by an `Address` message for each of its addresses.
+pub fn dump_interfaces() -> Result<Vec<Header>, ReadError> {
+    let mut mib = [CTL_NET, PF_ROUTE, 0, 0, NET_RT_IFLIST, 0];
+    let buf = read_sysctl(&mut mib)?;
+    log::debug!("read {} bytes of interfaces", buf.len());
+    parse_messages(&buf)
+}
@@ -0,0 +1,195 @@
+//! A copy of the kernel's routing table, for finding which route a
+//! destination would take.
+
+use std::net::IpAddr;
+
+use ipnetwork::IpNetwork;
+
+use crate::addresses::SockAddr;
+use crate::header::Header;
+use crate::route::{MessageType, RouteInfo};
+
+#[derive(Clone, Debug)]
+pub struct Route {
+    pub index: u16,
+    pub destination: IpNetwork,
+    pub gateway: Option<SockAddr>,
+    pub is_up: bool,
+    /// Set on reject and blackhole routes, which match traffic only to
+    /// refuse it.
+    pub is_reject: bool,
+    /// Set on routes which only apply to traffic bound to their interface
+    pub is_scoped: bool,
+}
+
+impl Route {
+    /// Reads a route from a route message. Neighbour entries and routes
+    /// without a usable destination are skipped.
+    pub fn from_route(info: &RouteInfo) -> Option<Self> {
+        if info.flags.is_llinfo() {
+            return None;
+        }
+
+        let destination = match info.addrs.destination_network() {
+            Ok(net) => net?,
+            Err(e) => {
+                log::warn!("ignoring route with bad netmask: {e}");
+                return None;
+            }
+        };
+
+        Some(Self {
+            index: info.index,
+            destination,
+            gateway: info.addrs.gateway.clone(),
+            is_up: info.flags.is_up(),
+            is_reject: info.flags.is_reject(),
+            is_scoped: info.flags.is_ifscope(),
+        })
+    }
+
+    pub fn is_default(&self) -> bool {
+        self.destination.prefix() == 0
+    }
+}
+
+/// Routes, kept up to date from route messages.
+#[derive(Debug, Default)]
+pub struct RoutingTable {
+    routes: Vec<Route>,
+}
+
+impl RoutingTable {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Applies a message to the table. Returns the affected route if the
+    /// message added or changed one.
+    pub fn update(&mut self, h: &Header) -> Option<&Route> {
+        let info = match h {
+            Header::Route(info) => info,
+            _ => return None,
+        };
+        let route = Route::from_route(info)?;
+
+        self.routes
+            .retain(|r| !(r.index == route.index && r.destination == route.destination));
+        match info.operation {
+            MessageType::Delete => {
+                log::debug!("route {} removed from {}", route.destination, route.index);
+                None
+            }
+            _ => {
+                log::debug!("route {} added to {}", route.destination, route.index);
+                self.routes.push(route);
+                self.routes.last()
+            }
+        }
+    }
+
+    pub fn routes(&self) -> impl Iterator<Item = &Route> {
+        self.routes.iter()
+    }
+
+    /// Finds the route the kernel would pick for a destination: the most
+    /// specific one which is up, ignoring scoped routes. This may be a reject
+    /// route.
+    pub fn lookup(&self, ip: IpAddr) -> Option<&Route> {
+        self.routes
+            .iter()
+            .filter(|r| r.is_up && !r.is_scoped && r.destination.contains(ip))
+            .max_by_key(|r| r.destination.prefix())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn route(index: u16, destination: &str) -> Route {
+        Route {
+            index,
+            destination: destination.parse().unwrap(),
+            gateway: None,
+            is_up: true,
+            is_reject: false,
+            is_scoped: false,
+        }
+    }
+
+    fn lookup(table: &RoutingTable, ip: &str) -> Option<(u16, String)> {
+        table
+            .lookup(ip.parse().unwrap())
+            .map(|r| (r.index, r.destination.to_string()))
+    }
+
+    #[test]
+    fn longest_prefix_wins() {
+        let table = RoutingTable {
+            routes: vec![
+                route(1, "0.0.0.0/0"),
+                route(2, "10.0.0.0/8"),
+                route(3, "10.50.0.0/16"),
+                route(4, "::/0"),
+                route(5, "2001:db8::/32"),
+            ],
+        };
+
+        assert_eq!(
+            lookup(&table, "10.50.3.4"),
+            Some((3, "10.50.0.0/16".into()))
+        );
+        assert_eq!(lookup(&table, "10.1.2.3"), Some((2, "10.0.0.0/8".into())));
+        assert_eq!(lookup(&table, "192.0.2.1"), Some((1, "0.0.0.0/0".into())));
+        assert_eq!(
+            lookup(&table, "2001:db8::1"),
+            Some((5, "2001:db8::/32".into()))
+        );
+        assert_eq!(lookup(&table, "2001:db9::1"), Some((4, "::/0".into())));
+    }
+
+    #[test]
+    fn no_matching_route() {
+        let table = RoutingTable {
+            routes: vec![route(1, "10.0.0.0/8")],
+        };
+        assert_eq!(lookup(&table, "192.0.2.1"), None);
+        assert_eq!(lookup(&table, "2001:db8::1"), None);
+    }
+
+    #[test]
+    fn down_routes_are_ignored() {
+        let mut down = route(2, "10.50.0.0/16");
+        down.is_up = false;
+        let table = RoutingTable {
+            routes: vec![route(1, "10.0.0.0/8"), down],
+        };
+        assert_eq!(lookup(&table, "10.50.3.4"), Some((1, "10.0.0.0/8".into())));
+    }
+
+    #[test]
+    fn reject_routes_are_still_found() {
+        let mut reject = route(2, "10.50.0.0/16");
+        reject.is_reject = true;
+        let table = RoutingTable {
+            routes: vec![route(1, "0.0.0.0/0"), reject],
+        };
+
+        let found = table.lookup("10.50.3.4".parse().unwrap()).unwrap();
+        assert_eq!(found.index, 2);
+        assert!(found.is_reject);
+    }
+
+    #[test]
+    fn scoped_routes_are_skipped() {
+        // e.g. a second default route which is only for traffic bound to its
+        // interface
+        let mut scoped = route(2, "10.50.0.0/16");
+        scoped.is_scoped = true;
+        let table = RoutingTable {
+            routes: vec![route(1, "0.0.0.0/0"), scoped],
+        };
+        assert_eq!(lookup(&table, "10.50.3.4"), Some((1, "0.0.0.0/0".into())));
+    }
+}
@@ -0,0 +1,396 @@
+//! Classifying addresses by family and scope, and filtering on those.
+
+use std::fmt;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::str::FromStr;
+
+use ipnetwork::{IpNetwork, Ipv4Network, Ipv6Network};
+
+lazy_static::lazy_static! {
+    static ref LOCAL_IPV6_ADDR: Ipv6Addr = Ipv6Addr::from([0, 0, 0, 0, 0, 0, 0, 1]);
+    static ref LOCAL_IPV4_RANGE: Ipv4Network = Ipv4Network::new(Ipv4Addr::from([127, 0, 0, 0]), 8).unwrap();
+
+    // Link local address range: 169.254.0.0/16
+    // https://en.wikipedia.org/wiki/Link-local_address#IPv4
+    static ref LINK_LOCAL_IPV4_RANGE: Ipv4Network = Ipv4Network::new(Ipv4Addr::from([169, 254, 0, 0]), 16).unwrap();
+    // Link local address range: fe80::/10
+    // https://en.wikipedia.org/wiki/Link-local_address#IPv6
+    static ref LINK_LOCAL_IPV6_RANGE: Ipv6Network = Ipv6Network::new(Ipv6Addr::from([65152, 0, 0, 0, 0, 0, 0, 0]), 10).unwrap();
+
+    // Private address ranges, https://datatracker.ietf.org/doc/html/rfc1918
+    static ref PRIVATE_IPV4_RANGES: [Ipv4Network; 3] = [
+        Ipv4Network::new(Ipv4Addr::from([10, 0, 0, 0]), 8).unwrap(),
+        Ipv4Network::new(Ipv4Addr::from([172, 16, 0, 0]), 12).unwrap(),
+        Ipv4Network::new(Ipv4Addr::from([192, 168, 0, 0]), 16).unwrap(),
+    ];
+    // Carrier-grade NAT range: 100.64.0.0/10
+    // https://datatracker.ietf.org/doc/html/rfc6598
+    static ref CGNAT_IPV4_RANGE: Ipv4Network = Ipv4Network::new(Ipv4Addr::from([100, 64, 0, 0]), 10).unwrap();
+    // Unique local address range: fc00::/7
+    // https://datatracker.ietf.org/doc/html/rfc4193
+    static ref ULA_IPV6_RANGE: Ipv6Network = Ipv6Network::new(Ipv6Addr::from([0xfc00, 0, 0, 0, 0, 0, 0, 0]), 7).unwrap();
+
+    static ref LOOPBACK_RANGES: [IpNetwork; 2] = [
+        IpNetwork::V4(*LOCAL_IPV4_RANGE),
+        IpNetwork::V6(Ipv6Network::new(*LOCAL_IPV6_ADDR, 128).unwrap()),
+    ];
+    static ref LINK_LOCAL_RANGES: [IpNetwork; 2] = [
+        IpNetwork::V4(*LINK_LOCAL_IPV4_RANGE),
+        IpNetwork::V6(*LINK_LOCAL_IPV6_RANGE),
+    ];
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AddressFamily {
+    Inet,
+    Inet6,
+}
+
+impl AddressFamily {
+    pub fn of(ip: IpAddr) -> Self {
+        match ip {
+            IpAddr::V4(_) => Self::Inet,
+            IpAddr::V6(_) => Self::Inet6,
+        }
+    }
+}
+
+impl FromStr for AddressFamily {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "inet" => Ok(Self::Inet),
+            "inet6" => Ok(Self::Inet6),
+            _ => Err(format!("invalid family '{s}' (expected inet or inet6)")),
+        }
+    }
+}
+
+impl fmt::Display for AddressFamily {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Inet => write!(f, "inet"),
+            Self::Inet6 => write!(f, "inet6"),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AddressScope {
+    /// 127.0.0.0/8, ::1
+    Loopback,
+    /// 169.254.0.0/16, fe80::/10
+    Link,
+    /// 10.0.0.0/8, 172.16.0.0/12, 192.168.0.0/16
+    Private,
+    /// 100.64.0.0/10
+    Cgnat,
+    /// fc00::/7
+    Ula,
+    /// Anything else
+    Global,
+}
+
+impl AddressScope {
+    pub fn of(ip: IpAddr) -> Self {
+        match ip {
+            IpAddr::V4(ip) if LOCAL_IPV4_RANGE.contains(ip) => Self::Loopback,
+            IpAddr::V4(ip) if LINK_LOCAL_IPV4_RANGE.contains(ip) => Self::Link,
+            IpAddr::V4(ip) if PRIVATE_IPV4_RANGES.iter().any(|r| r.contains(ip)) => Self::Private,
+            IpAddr::V4(ip) if CGNAT_IPV4_RANGE.contains(ip) => Self::Cgnat,
+            IpAddr::V6(ip) if ip == *LOCAL_IPV6_ADDR => Self::Loopback,
+            IpAddr::V6(ip) if LINK_LOCAL_IPV6_RANGE.contains(ip) => Self::Link,
+            IpAddr::V6(ip) if ULA_IPV6_RANGE.contains(ip) => Self::Ula,
+            _ => Self::Global,
+        }
+    }
+}
+
+impl FromStr for AddressScope {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "link" => Ok(Self::Link),
+            "private" => Ok(Self::Private),
+            "cgnat" => Ok(Self::Cgnat),
+            "ula" => Ok(Self::Ula),
+            "global" => Ok(Self::Global),
+            _ => Err(format!(
+                "invalid scope '{s}' (expected global, ula, private, cgnat or link)"
+            )),
+        }
+    }
+}
+
+impl fmt::Display for AddressScope {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Loopback => write!(f, "loopback"),
+            Self::Link => write!(f, "link"),
+            Self::Private => write!(f, "private"),
+            Self::Cgnat => write!(f, "cgnat"),
+            Self::Ula => write!(f, "ula"),
+            Self::Global => write!(f, "global"),
+        }
+    }
+}
+
+/// Narrows down which addresses satisfy a condition, from `family=` and
+/// `scope=` options.
+#[derive(Clone, Debug, Default)]
+pub struct AddressFilter {
+    pub family: Option<AddressFamily>,
+    pub scope: Option<AddressScope>,
+}
+
+impl AddressFilter {
+    /// Applies a `key=value` option to this filter.
+    pub fn qualify(&mut self, key: &str, value: &str) -> Result<(), String> {
+        match key {
+            "family" => self.family = Some(value.parse()?),
+            "scope" => self.scope = Some(value.parse()?),
+            _ => return Err(format!("unknown option '{key}' (expected family or scope)")),
+        }
+        Ok(())
+    }
+
+    pub fn matches(&self, policy: &AddressPolicy, ip: IpAddr) -> bool {
+        if self.family.is_some_and(|f| f != AddressFamily::of(ip)) {
+            return false;
+        }
+
+        match self.scope {
+            // Asking for a scope overrides --allow-link-local, but not
+            // --exclude-range
+            Some(scope) => scope == AddressScope::of(ip) && !policy.is_excluded(ip),
+            // Without a scope, we want anything the policy counts as usable
+            None => policy.is_usable(ip),
+        }
+    }
+}
+
+impl fmt::Display for AddressFilter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let Some(family) = self.family {
+            write!(f, ",family={family}")?;
+        }
+        if let Some(scope) = self.scope {
+            write!(f, ",scope={scope}")?;
+        }
+        Ok(())
+    }
+}
+
+/// Which addresses and routes count towards being online, from
+/// `--exclude-range`, `--include-range` and `--allow-link-local`.
+///
+/// Loopback never counts. Otherwise, the most specific range given decides,
+/// and without one, everything but link-local counts.
+#[derive(Clone, Debug, Default)]
+pub struct AddressPolicy {
+    pub exclude: Vec<IpNetwork>,
+    pub include: Vec<IpNetwork>,
+    pub allow_link_local: bool,
+}
+
+impl AddressPolicy {
+    pub fn is_usable(&self, ip: IpAddr) -> bool {
+        let b = self.is_usable_network(&ip.into());
+        log::trace!("{ip} usable? {b}");
+        b
+    }
+
+    /// Like `is_usable`, but for whole networks, e.g. route destinations: a
+    /// network only counts as local if it is entirely within a local range.
+    pub fn is_usable_network(&self, net: &IpNetwork) -> bool {
+        if is_subnet_of(net, &*LOOPBACK_RANGES) {
+            return false;
+        }
+        if let Some(usable) = self.explicit(net) {
+            return usable;
+        }
+        self.allow_link_local || !is_subnet_of(net, &*LINK_LOCAL_RANGES)
+    }
+
+    /// Whether `ip` is in an excluded range, ignoring link-local addresses.
+    pub fn is_excluded(&self, ip: IpAddr) -> bool {
+        self.is_excluded_network(&ip.into())
+    }
+
+    /// Whether `net` lies within an excluded range (and no more specific
+    /// included one).
+    pub fn is_excluded_network(&self, net: &IpNetwork) -> bool {
+        self.explicit(net) == Some(false)
+    }
+
+    /// What the most specific range containing `net` says about it, if any
+    /// does. Includes win ties, as they're the more deliberate choice.
+    fn explicit(&self, net: &IpNetwork) -> Option<bool> {
+        let most_specific = |ranges: &[IpNetwork]| {
+            ranges
+                .iter()
+                .filter(|r| within(net, r))
+                .map(|r| r.prefix())
+                .max()
+        };
+        match (most_specific(&self.include), most_specific(&self.exclude)) {
+            (Some(include), Some(exclude)) => Some(include >= exclude),
+            (Some(_), None) => Some(true),
+            (None, Some(_)) => Some(false),
+            (None, None) => None,
+        }
+    }
+}
+
+fn is_subnet_of(net: &IpNetwork, ranges: &[IpNetwork]) -> bool {
+    ranges.iter().any(|r| within(net, r))
+}
+
+/// Whether `net` lies entirely within `range`.
+fn within(net: &IpNetwork, range: &IpNetwork) -> bool {
+    match (net, range) {
+        (IpNetwork::V4(n), IpNetwork::V4(r)) => n.is_subnet_of(*r),
+        (IpNetwork::V6(n), IpNetwork::V6(r)) => n.is_subnet_of(*r),
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn scopes() {
+        let cases = [
+            ("127.0.0.1", AddressScope::Loopback),
+            ("::1", AddressScope::Loopback),
+            ("169.254.10.20", AddressScope::Link),
+            ("fe80::1", AddressScope::Link),
+            ("10.1.2.3", AddressScope::Private),
+            ("172.16.0.1", AddressScope::Private),
+            ("172.31.255.255", AddressScope::Private),
+            ("192.168.1.1", AddressScope::Private),
+            ("100.64.0.1", AddressScope::Cgnat),
+            ("100.127.255.255", AddressScope::Cgnat),
+            ("fd12:3456::1", AddressScope::Ula),
+            ("172.32.0.1", AddressScope::Global),
+            ("100.128.0.1", AddressScope::Global),
+            ("8.8.8.8", AddressScope::Global),
+            ("2001:db8::1", AddressScope::Global),
+        ];
+        for (addr, scope) in cases {
+            assert_eq!(AddressScope::of(ip(addr)), scope, "{addr}");
+        }
+    }
+
+    #[test]
+    fn default_filter_skips_local_addresses() {
+        let filter = AddressFilter::default();
+        let policy = AddressPolicy::default();
+        assert!(filter.matches(&policy, ip("192.168.1.10")));
+        assert!(filter.matches(&policy, ip("2001:db8::1")));
+        assert!(!filter.matches(&policy, ip("169.254.1.1")));
+        assert!(!filter.matches(&policy, ip("fe80::1")));
+        assert!(!filter.matches(&policy, ip("127.0.0.1")));
+    }
+
+    #[test]
+    fn filter_by_family_and_scope() {
+        let policy = AddressPolicy::default();
+        let mut filter = AddressFilter::default();
+        filter.qualify("family", "inet6").unwrap();
+        assert!(filter.matches(&policy, ip("2001:db8::1")));
+        assert!(!filter.matches(&policy, ip("192.0.2.1")));
+
+        filter.qualify("scope", "ula").unwrap();
+        assert!(filter.matches(&policy, ip("fd00::1")));
+        assert!(!filter.matches(&policy, ip("2001:db8::1")));
+
+        // Asking for link-local addresses gets them
+        let mut filter = AddressFilter::default();
+        filter.qualify("scope", "link").unwrap();
+        assert!(filter.matches(&policy, ip("fe80::1")));
+        assert!(!filter.matches(&policy, ip("10.0.0.1")));
+    }
+
+    #[test]
+    fn bad_filter_options() {
+        let mut filter = AddressFilter::default();
+        assert!(filter.qualify("family", "inet4").is_err());
+        assert!(filter.qualify("scope", "loopback").is_err());
+        assert!(filter.qualify("colour", "blue").is_err());
+    }
+
+    fn net(s: &str) -> IpNetwork {
+        s.parse().unwrap()
+    }
+
+    fn policy(exclude: &[&str], include: &[&str]) -> AddressPolicy {
+        AddressPolicy {
+            exclude: exclude.iter().map(|s| net(s)).collect(),
+            include: include.iter().map(|s| net(s)).collect(),
+            allow_link_local: false,
+        }
+    }
+
+    #[test]
+    fn most_specific_range_wins() {
+        let p = policy(
+            &["172.16.0.0/12", "10.9.0.0/16"],
+            &["172.17.5.0/24", "10.0.0.0/8"],
+        );
+        assert_eq!(p.explicit(&net("8.8.8.8/32")), None);
+        assert_eq!(p.explicit(&net("172.17.0.2/32")), Some(false));
+        assert_eq!(p.explicit(&net("172.17.5.9/32")), Some(true));
+        assert_eq!(p.explicit(&net("10.9.1.1/32")), Some(false));
+        assert_eq!(p.explicit(&net("10.10.1.1/32")), Some(true));
+
+        assert!(p.is_excluded(ip("172.17.0.2")));
+        assert!(!p.is_usable(ip("172.17.0.2")));
+        assert!(p.is_usable(ip("172.17.5.9")));
+        assert!(p.is_usable(ip("8.8.8.8")));
+    }
+
+    #[test]
+    fn includes_win_ties() {
+        let p = policy(&["192.168.64.0/24"], &["192.168.64.0/24"]);
+        assert_eq!(p.explicit(&net("192.168.64.2/32")), Some(true));
+        assert!(!p.is_excluded(ip("192.168.64.2")));
+    }
+
+    #[test]
+    fn networks_have_to_be_within_a_range() {
+        let p = policy(&["172.17.0.0/16"], &[]);
+        assert!(p.is_excluded_network(&net("172.17.1.0/24")));
+        // Only partly excluded
+        assert!(!p.is_excluded_network(&net("172.16.0.0/12")));
+        assert!(p.is_usable_network(&net("0.0.0.0/0")));
+        // Families don't mix
+        assert!(!p.is_excluded_network(&net("::/0")));
+    }
+
+    #[test]
+    fn local_ranges() {
+        // Loopback can't be included
+        let p = policy(&[], &["127.0.0.0/8"]);
+        assert!(!p.is_usable(ip("127.0.0.1")));
+
+        // Link-local takes --allow-link-local or an include
+        let p = policy(&[], &["169.254.0.0/16"]);
+        assert!(p.is_usable(ip("169.254.1.1")));
+        assert!(!p.is_usable(ip("fe80::1")));
+        assert!(!p.is_excluded(ip("fe80::1")));
+        let p = AddressPolicy {
+            allow_link_local: true,
+            ..policy(&["fe80::/64"], &[])
+        };
+        assert!(p.is_usable(ip("169.254.1.1")));
+        assert!(!p.is_usable(ip("fe80::1")));
+    }
+}
@@ -0,0 +1,627 @@
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, UdpSocket};
+use std::os::fd::AsRawFd;
+use std::time::Duration;
+
+use ipnetwork::IpNetwork;
+use libroute::table::Route;
+use nix::errno::Errno;
+use nix::sys::socket::{
+    bind,
+    setsockopt,
+    socket,
+    sockopt,
+    AddressFamily,
+    SockFlag,
+    SockType,
+    SockaddrStorage,
+};
+
+use crate::address::{AddressFilter, AddressScope};
+use crate::dns::DnsCheck;
+use crate::flags::WaitConditionFlag;
+use crate::gateway::GatewayFilter;
+use crate::http::HttpCheck;
+use crate::interface::InterfaceSpec;
+use crate::ping::PingCheck;
+use crate::probe::Stats;
+use crate::report::Report;
+use crate::state::{Address, NetworkState};
+use crate::tcp::TcpCheck;
+
+#[derive(Clone, Debug)]
+pub enum InterfaceCondition {
+    HasAddress(AddressFilter),
+    HasRoute,
+    /// Up and running, i.e. has carrier
+    LinkUp,
+}
+
+/// A condition in an `all`, `any` or `quorum`, with how it did the last time
+/// it was checked, which is what gets reported.
+#[derive(Debug)]
+pub struct Member {
+    /// What the condition was given as, for the report
+    pub label: String,
+    pub condition: WaitCondition,
+    pub passed: bool,
+}
+
+impl Member {
+    fn from_flags(flags: Vec<WaitConditionFlag>) -> Vec<Self> {
+        flags
+            .into_iter()
+            .map(|flag| Self {
+                label: flag.to_string(),
+                condition: WaitCondition::from(flag),
+                passed: false,
+            })
+            .collect()
+    }
+
+    /// Checks the member, and gives whether it held.
+    fn check(&mut self, state: &NetworkState) -> bool {
+        self.passed = self.condition.holds(state);
+        self.passed
+    }
+}
+
+/// Checks every member, so that each one's result is current for the report,
+/// and gives how many held. Probes run in the background, so this is quick.
+fn check_all(members: &mut [Member], state: &NetworkState) -> usize {
+    members
+        .iter_mut()
+        .map(|m| usize::from(m.check(state)))
+        .sum()
+}
+
+#[derive(Debug)]
+pub enum WaitCondition {
+    All(Vec<Member>),
+    Any(Vec<Member>),
+    Not(Box<WaitCondition>),
+    /// How many members must hold, and the members
+    Quorum(usize, Vec<Member>),
+
+    CanBind(SocketAddr),
+    DefaultRoute(GatewayFilter),
+    GatewayReachable(GatewayFilter),
+    DnsReady(DnsCheck),
+    HasAddress(IpNetwork),
+    HttpOnline(HttpCheck),
+    Interface(InterfaceCondition, InterfaceSpec),
+    Ping(PingCheck),
+    RouteTo(IpAddr, Option<InterfaceSpec>),
+    Routable(IpAddr, Option<AddressScope>),
+    TcpConnect(TcpCheck),
+}
+
+impl From<WaitConditionFlag> for WaitCondition {
+    fn from(flag: WaitConditionFlag) -> Self {
+        match flag {
+            WaitConditionFlag::All(flags) => Self::All(Member::from_flags(flags)),
+            WaitConditionFlag::Any(flags) => Self::Any(Member::from_flags(flags)),
+            WaitConditionFlag::Not(flag) => Self::Not(Box::new(Self::from(*flag))),
+            WaitConditionFlag::Quorum(needed, flags) => {
+                Self::Quorum(needed, Member::from_flags(flags))
+            }
+            WaitConditionFlag::CanBind(addr) => Self::CanBind(addr),
+            WaitConditionFlag::DefaultRouteExists(filter) => Self::DefaultRoute(filter),
+            WaitConditionFlag::GatewayReachable(filter) => Self::GatewayReachable(filter),
+            WaitConditionFlag::DnsReady(options) => Self::DnsReady(DnsCheck::new(options)),
+            WaitConditionFlag::HasAddress(net) => Self::HasAddress(net),
+            WaitConditionFlag::HttpOnline(options) => Self::HttpOnline(HttpCheck::new(options)),
+            WaitConditionFlag::InterfaceHasAddress(spec, filter) => {
+                Self::Interface(InterfaceCondition::HasAddress(filter), spec)
+            }
+            WaitConditionFlag::InterfaceHasRoute(spec) => {
+                Self::Interface(InterfaceCondition::HasRoute, spec)
+            }
+            WaitConditionFlag::InterfaceLinkUp(spec) => {
+                Self::Interface(InterfaceCondition::LinkUp, spec)
+            }
+            WaitConditionFlag::Ping(options) => Self::Ping(PingCheck::new(options)),
+            WaitConditionFlag::RouteTo(ip, via) => Self::RouteTo(ip, via),
+            WaitConditionFlag::Routable(ip, scope) => Self::Routable(ip, scope),
+            WaitConditionFlag::TcpConnect(options) => Self::TcpConnect(TcpCheck::new(options)),
+        }
+    }
+}
+
+impl WaitCondition {
+    /// Whether this condition holds for the network as it is right now.
+    ///
+    /// Conditions which probe the network do so here if they're due to, and
+    /// otherwise go by their last attempt.
+    pub fn holds(&mut self, state: &NetworkState) -> bool {
+        match self {
+            Self::All(members) => check_all(members, state) == members.len(),
+            Self::Any(members) => check_all(members, state) > 0,
+            Self::Not(cond) => !cond.holds(state),
+            Self::Quorum(needed, members) => {
+                let passed = check_all(members, state);
+                log::trace!("{passed} of {} passed, need {needed}", members.len());
+                passed >= *needed
+            }
+            Self::CanBind(addr) => bindable(state, *addr).is_some(),
+            Self::DnsReady(check) => check.holds(state),
+            Self::DefaultRoute(filter) => default_route(state, filter).is_some(),
+            Self::GatewayReachable(filter) => reachable_gateway(state, filter).is_some(),
+            Self::HasAddress(net) => address_in(state, net).is_some(),
+            Self::HttpOnline(check) => check.holds(state),
+            Self::Interface(cond, spec) => matching_interface(state, cond, spec).is_some(),
+            Self::Ping(check) => check.holds(state),
+            Self::RouteTo(ip, via) => routable_via(state, *ip, via.as_ref()).is_some(),
+            Self::Routable(ip, scope) => usable_source(state, *ip, *scope).is_some(),
+            Self::TcpConnect(check) => check.holds(),
+        }
+    }
+
+    /// Adds what made this condition hold (e.g. which interface matched) to
+    /// the report.
+    pub fn report(&mut self, state: &NetworkState, report: &mut Report) {
+        match self {
+            // Going by how each member did when last checked, since
+            // checking again could give a different answer to the one which
+            // ended the wait
+            Self::All(members) | Self::Any(members) => {
+                for member in members.iter_mut().filter(|m| m.passed) {
+                    member.condition.report(state, report);
+                }
+            }
+            // Nothing matched, so there's nothing to say
+            Self::Not(_) => (),
+            Self::Quorum(_, members) => {
+                for member in members {
+                    let stats = member.condition.stats();
+                    report.add_probe(&member.label, member.passed, stats);
+                    if member.passed {
+                        member.condition.report(state, report);
+                    }
+                }
+            }
+            Self::DnsReady(_) => (),
+            Self::CanBind(addr) => {
+                if let Some(a) = bindable(state, *addr) {
+                    report.add_interface(state, a.index);
+                }
+            }
+            Self::HttpOnline(check) => {
+                let label = format!("http={}", check.options);
+                report.add_probe(&label, true, Some(check.stats()));
+            }
+            Self::Ping(check) => {
+                let label = format!("ping={}", check.options);
+                report.add_probe(&label, true, Some(check.stats()));
+            }
+            Self::TcpConnect(check) => {
+                let label = format!("tcp={}", check.options);
+                report.add_probe(&label, true, Some(check.stats()));
+            }
+            Self::DefaultRoute(filter) => {
+                if let Some(route) = default_route(state, filter) {
+                    report.add_gateway(route);
+                }
+            }
+            Self::GatewayReachable(filter) => {
+                if let Some(route) = reachable_gateway(state, filter) {
+                    report.add_gateway(route);
+                }
+            }
+            Self::HasAddress(net) => {
+                if let Some(a) = address_in(state, net) {
+                    report.add_interface(state, a.index);
+                }
+            }
+            Self::Interface(cond, spec) => {
+                if let Some(index) = matching_interface(state, cond, spec) {
+                    report.add_interface(state, index);
+                }
+            }
+            Self::RouteTo(ip, via) => {
+                if let Some(index) = routable_via(state, *ip, via.as_ref()) {
+                    report.add_interface(state, index);
+                }
+            }
+            Self::Routable(ip, scope) => {
+                let source = usable_source(state, *ip, *scope);
+                let owner = state.addresses().find(|a| Some(a.network.ip()) == source);
+                if let Some(a) = owner {
+                    report.add_interface(state, a.index);
+                }
+            }
+        }
+    }
+}
+
+impl WaitCondition {
+    /// How long until the soonest probe in this condition is due to run
+    /// again, if there are any.
+    pub fn next_probe_in(&self) -> Option<Duration> {
+        match self {
+            Self::All(members) | Self::Any(members) | Self::Quorum(_, members) => members
+                .iter()
+                .filter_map(|m| m.condition.next_probe_in())
+                .min(),
+            Self::Not(cond) => cond.next_probe_in(),
+            Self::DnsReady(check) => check.next_probe_in(),
+            Self::HttpOnline(check) => check.next_probe_in(),
+            Self::Ping(check) => check.next_probe_in(),
+            Self::TcpConnect(check) => check.next_probe_in(),
+            _ => None,
+        }
+    }
+
+    /// Lets probes know the network has changed, so any which are failing
+    /// can try again soon.
+    pub fn network_changed(&mut self) {
+        match self {
+            Self::All(members) | Self::Any(members) | Self::Quorum(_, members) => members
+                .iter_mut()
+                .for_each(|m| m.condition.network_changed()),
+            Self::Not(cond) => cond.network_changed(),
+            Self::DnsReady(check) => check.network_changed(),
+            Self::HttpOnline(check) => check.network_changed(),
+            Self::Ping(check) => check.network_changed(),
+            Self::TcpConnect(check) => check.network_changed(),
+            _ => (),
+        }
+    }
+
+    /// Whether this condition depends on `address`, i.e. it's on an interface
+    /// the condition names, or is one it asks for.
+    pub fn watches(&self, state: &NetworkState, address: &Address) -> bool {
+        match self {
+            Self::All(members) | Self::Any(members) | Self::Quorum(_, members) => {
+                members.iter().any(|m| m.condition.watches(state, address))
+            }
+            Self::Not(cond) => cond.watches(state, address),
+            Self::CanBind(addr) => addr.ip() == address.network.ip(),
+            Self::HasAddress(net) => net.contains(address.network.ip()),
+            Self::Interface(_, spec) | Self::RouteTo(_, Some(spec)) => {
+                spec.matches(state, address.index)
+            }
+            _ => false,
+        }
+    }
+
+    /// What a probe measured, if this is one which measures round trip times.
+    pub fn stats(&self) -> Option<Stats> {
+        match self {
+            Self::HttpOnline(check) => Some(check.stats()),
+            Self::Ping(check) => Some(check.stats()),
+            Self::TcpConnect(check) => Some(check.stats()),
+            _ => None,
+        }
+    }
+
+    /// Whether any `http` condition was last answered by a captive portal.
+    pub fn behind_captive_portal(&self) -> bool {
+        match self {
+            Self::All(members) | Self::Any(members) | Self::Quorum(_, members) => {
+                members.iter().any(|m| m.condition.behind_captive_portal())
+            }
+            Self::Not(cond) => cond.behind_captive_portal(),
+            Self::HttpOnline(check) => check.is_captive(),
+            _ => false,
+        }
+    }
+
+    /// Whether this condition depends on the resolver configuration.
+    pub fn uses_dns(&self) -> bool {
+        match self {
+            Self::All(members) | Self::Any(members) | Self::Quorum(_, members) => {
+                members.iter().any(|m| m.condition.uses_dns())
+            }
+            Self::Not(cond) => cond.uses_dns(),
+            Self::DnsReady(_) => true,
+            _ => false,
+        }
+    }
+
+    /// Whether this condition looks at default gateways' neighbour entries.
+    pub fn uses_neighbors(&self) -> bool {
+        match self {
+            Self::All(members) | Self::Any(members) | Self::Quorum(_, members) => {
+                members.iter().any(|m| m.condition.uses_neighbors())
+            }
+            Self::Not(cond) => cond.uses_neighbors(),
+            Self::GatewayReachable(_) => true,
+            Self::DefaultRoute(filter) => filter.mac.is_some(),
+            _ => false,
+        }
+    }
+}
+
+/// Default routes which are in use (i.e. not scoped to an interface), and
+/// go via a gateway matching the filter.
+fn default_routes<'a>(
+    state: &'a NetworkState,
+    filter: &'a GatewayFilter,
+) -> impl Iterator<Item = &'a Route> {
+    state
+        .routes()
+        .filter(|r| r.is_default() && r.is_up && !r.is_scoped)
+        .filter(|r| filter.matches(state, r))
+        // A gateway in an excluded range, e.g. a VM host's bridge, isn't a
+        // way online
+        .filter(|r| !is_excluded_route(state, r))
+}
+
+fn default_route<'a>(state: &'a NetworkState, filter: &'a GatewayFilter) -> Option<&'a Route> {
+    let found = default_routes(state, filter).find(|r| r.gateway.is_some());
+    log::trace!("default route? {}", found.is_some());
+    found
+}
+
+fn reachable_gateway<'a>(state: &'a NetworkState, filter: &'a GatewayFilter) -> Option<&'a Route> {
+    // Only routes via a router have a neighbour to resolve. We can't tell
+    // anything from routes which point directly at an interface.
+    default_routes(state, filter).find(|r| {
+        let gw = match r.gateway.as_ref().and_then(|gw| gw.ip()) {
+            Some(gw) => gw,
+            None => return false,
+        };
+        let resolved = state.neighbors().is_resolved(&gw);
+        log::trace!("gateway {gw} resolved? {resolved}");
+        resolved
+    })
+}
+
+fn address_in<'a>(state: &'a NetworkState, net: &IpNetwork) -> Option<&'a Address> {
+    // No locality checks here: if someone asks for a link-local subnet, they
+    // really do want one.
+    let found = state.addresses().find(|a| net.contains(a.network.ip()));
+    if let Some(a) = found {
+        log::trace!("{} on {} is within {net}", a.network.ip(), a.index);
+    }
+    found
+}
+
+/// Gives the address entry for `addr`, if it's assigned, has passed
+/// duplicate address detection, and a trial bind to it works. The socket is
+/// closed straight away, releasing the port.
+///
+/// Only `EADDRNOTAVAIL` means the address isn't ready. Anything else, such as
+/// the port being in use, is put down to the port, so the address is tried
+/// again with any port.
+fn bindable(state: &NetworkState, addr: SocketAddr) -> Option<&Address> {
+    let ip = addr.ip();
+    let assigned = match state.addresses().find(|a| a.network.ip() == ip) {
+        Some(a) if a.has_passed_dad() => a,
+        Some(_) => {
+            log::trace!("{ip} hasn't passed duplicate address detection");
+            return None;
+        }
+        None => {
+            log::trace!("{ip} isn't assigned");
+            return None;
+        }
+    };
+
+    let bound = match trial_bind(addr) {
+        Err(e) if e != Errno::EADDRNOTAVAIL && addr.port() != 0 => {
+            log::trace!("couldn't bind to {addr} ({e}), trying any port");
+            trial_bind(SocketAddr::new(ip, 0))
+        }
+        bound => bound,
+    };
+    match bound {
+        Ok(()) => Some(assigned),
+        Err(e) => {
+            log::trace!("couldn't bind to {ip}: {e}");
+            None
+        }
+    }
+}
+
+/// Binds a socket to `addr`, and closes it again.
+fn trial_bind(addr: SocketAddr) -> nix::Result<()> {
+    let family = match addr {
+        SocketAddr::V4(_) => AddressFamily::Inet,
+        SocketAddr::V6(_) => AddressFamily::Inet6,
+    };
+    let fd = socket(family, SockType::Stream, SockFlag::empty(), None)?;
+    // Like the servers we're waiting for, so a port in TIME_WAIT doesn't
+    // count against us
+    setsockopt(&fd, sockopt::ReuseAddr, &true)?;
+    bind(fd.as_raw_fd(), &SockaddrStorage::from(addr))
+}
+
+/// Gives the source address the kernel would pick to reach `ip`, if it's
+/// usable (and has the given scope, if any).
+///
+/// Connecting a UDP socket makes the kernel choose a route and source address
+/// without sending anything, so this can be checked on every event. The
+/// source also has to be one of our addresses which has passed duplicate
+/// address detection, in case the kernel picks one which hasn't.
+fn usable_source(state: &NetworkState, ip: IpAddr, scope: Option<AddressScope>) -> Option<IpAddr> {
+    let bind: SocketAddr = match ip {
+        IpAddr::V4(_) => (Ipv4Addr::UNSPECIFIED, 0).into(),
+        IpAddr::V6(_) => (Ipv6Addr::UNSPECIFIED, 0).into(),
+    };
+    // Any port will do, as nothing is sent
+    let source = UdpSocket::bind(bind)
+        .and_then(|s| s.connect((ip, 9)).map(|_| s))
+        .and_then(|s| s.local_addr());
+    let source = match source {
+        Ok(addr) => addr.ip(),
+        Err(e) => {
+            log::trace!("no source address for {ip}: {e}");
+            return None;
+        }
+    };
+
+    let passed_dad = state
+        .addresses()
+        .find(|a| a.network.ip() == source)
+        .is_some_and(|a| a.has_passed_dad());
+    let usable = passed_dad
+        && match (AddressScope::of(source), scope) {
+            (source_scope, Some(want)) if source_scope != want => false,
+            // A link-local source can't reach anything beyond the link, so it
+            // only counts if asked for, whatever the address policy allows
+            (AddressScope::Link, want) => want.is_some() && !state.policy().is_excluded(source),
+            _ => state.policy().is_usable(source),
+        };
+    log::trace!("source address for {ip} is {source}, usable? {usable}");
+    usable.then_some(source)
+}
+
+/// Gives the index of the interface `ip` would be routed through, if it's
+/// routable (through an interface matching `via`, if given).
+fn routable_via(state: &NetworkState, ip: IpAddr, via: Option<&InterfaceSpec>) -> Option<u16> {
+    let route = match state.lookup_route(ip) {
+        Some(r) if r.is_reject => {
+            log::trace!("{ip} is rejected by {}", r.destination);
+            return None;
+        }
+        // e.g. a container bridge, which isn't a way to reach anything else
+        Some(r) if is_excluded_route(state, r) => {
+            log::trace!("{ip} routes via {}, which is excluded", r.destination);
+            return None;
+        }
+        Some(r) => r,
+        None => {
+            log::trace!("no route to {ip}");
+            return None;
+        }
+    };
+
+    let found = via
+        .map(|spec| spec.matches(state, route.index))
+        .unwrap_or(true);
+    log::trace!(
+        "{ip} routes via {} on {}: {found}",
+        route.destination,
+        route.index
+    );
+    found.then_some(route.index)
+}
+
+/// Whether a route's destination or gateway is in an excluded range.
+fn is_excluded_route(state: &NetworkState, route: &Route) -> bool {
+    let policy = state.policy();
+    let gateway = route.gateway.as_ref().and_then(|gw| gw.ip());
+    policy.is_excluded_network(&route.destination)
+        || gateway.is_some_and(|ip| policy.is_excluded(ip))
+}
+
+/// Gives the index of the first interface matching `spec` for which the
+/// condition holds. With a pattern, any matching interface will do.
+///
+/// Interfaces which are down don't count, even if they keep their addresses.
+fn matching_interface(
+    state: &NetworkState,
+    condition: &InterfaceCondition,
+    spec: &InterfaceSpec,
+) -> Option<u16> {
+    let wanted = |index| state.is_link_up(index) && spec.matches(state, index);
+    match condition {
+        InterfaceCondition::HasRoute => state
+            .routes()
+            .filter(|r| r.is_up && wanted(r.index))
+            .find(|r| state.policy().is_usable_network(&r.destination))
+            .map(|r| r.index),
+        InterfaceCondition::HasAddress(filter) => state
+            .addresses()
+            .filter(|a| wanted(a.index) && a.has_passed_dad())
+            .find(|a| filter.matches(state.policy(), a.network.ip()))
+            .map(|a| a.index),
+        InterfaceCondition::LinkUp => state
+            .links()
+            .filter(|l| l.is_up && l.is_running)
+            .find(|l| spec.matches(state, l.index))
+            .map(|l| l.index),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddrV4;
+
+    use libroute::addresses::{AddressSet, DataLinkAddr, SockAddr};
+    use libroute::header::Header;
+    use libroute::route::{MessageType, RouteInfo, RouteMetrics, RoutingFlags};
+    use nix::libc::{RTF_GATEWAY, RTF_HOST, RTF_IFSCOPE, RTF_LLINFO, RTF_UP};
+
+    use super::*;
+    use crate::address::AddressPolicy;
+
+    const GATEWAY: Ipv4Addr = Ipv4Addr::new(192, 168, 1, 1);
+
+    fn v4(ip: Ipv4Addr) -> SockAddr {
+        SockAddr::V4(SocketAddrV4::new(ip, 0))
+    }
+
+    fn route(flags: i32, destination: SockAddr, gateway: SockAddr) -> Header {
+        Header::Route(RouteInfo {
+            operation: MessageType::Add,
+            index: 4,
+            flags: RoutingFlags::from_raw(flags),
+            metrics: RouteMetrics::default(),
+            addrs: AddressSet {
+                destination: Some(destination),
+                gateway: Some(gateway),
+                netmask: None,
+                genmask: None,
+                broadcast: None,
+                interface_addr: None,
+                interface_link: None,
+            },
+        })
+    }
+
+    fn default_route(flags: i32) -> Header {
+        let mut h = route(flags, v4(Ipv4Addr::UNSPECIFIED), v4(GATEWAY));
+        if let Header::Route(info) = &mut h {
+            info.addrs.netmask = Some(IpAddr::V4(Ipv4Addr::UNSPECIFIED));
+        }
+        h
+    }
+
+    fn neighbor(mac: &[u8]) -> Header {
+        let link = DataLinkAddr {
+            index: 4,
+            link_layer_addr: mac.to_vec(),
+            interface_name: String::new(),
+        };
+        route(
+            RTF_UP | RTF_HOST | RTF_LLINFO,
+            v4(GATEWAY),
+            SockAddr::Link(link),
+        )
+    }
+
+    fn reachable(state: &NetworkState, filter: &GatewayFilter) -> Option<Option<IpAddr>> {
+        reachable_gateway(state, filter).map(|r| r.gateway.as_ref().and_then(|gw| gw.ip()))
+    }
+
+    #[test]
+    fn gateway_reachable() {
+        let mut state = NetworkState::new(AddressPolicy::default());
+        let filter = GatewayFilter::default();
+        state.update(&default_route(RTF_UP | RTF_GATEWAY));
+        assert_eq!(reachable(&state, &filter), None);
+
+        // Still resolving
+        state.update(&neighbor(&[]));
+        assert_eq!(reachable(&state, &filter), None);
+
+        state.update(&neighbor(&[0xaa, 0xbb, 0xcc, 0x00, 0x11, 0x22]));
+        assert_eq!(reachable(&state, &filter), Some(Some(GATEWAY.into())));
+
+        let other = GatewayFilter {
+            ip: Some(Ipv4Addr::new(192, 168, 1, 254).into()),
+            mac: None,
+        };
+        assert_eq!(reachable(&state, &other), None);
+    }
+
+    #[test]
+    fn scoped_gateways_are_ignored() {
+        let mut state = NetworkState::new(AddressPolicy::default());
+        state.update(&default_route(RTF_UP | RTF_GATEWAY | RTF_IFSCOPE));
+        state.update(&neighbor(&[0xaa, 0xbb, 0xcc, 0x00, 0x11, 0x22]));
+        assert_eq!(reachable(&state, &GatewayFilter::default()), None);
+    }
+}
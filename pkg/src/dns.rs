@@ -0,0 +1,336 @@
+//! Checking that DNS is configured, and optionally that it answers.
+//!
+//! resolv.conf is watched with kqueue (see `RouteSocket::watch_path`); Linux's
+//! inotify isn't supported.
+
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, UdpSocket};
+use std::path::Path;
+use std::time::{Duration, Instant, SystemTime};
+use std::{fmt, fs, io};
+
+use crate::address::AddressPolicy;
+use crate::probe::Probe;
+use crate::state::NetworkState;
+
+pub const RESOLV_CONF: &str = "/etc/resolv.conf";
+
+/// Where systemd-resolved listens, which is fine despite being loopback
+const RESOLVED_STUB: IpAddr = IpAddr::V4(Ipv4Addr::new(127, 0, 0, 53));
+
+/// How long to wait for an answer to each query
+const QUERY_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// The longest a label in a name can be
+const MAX_LABEL_LEN: usize = 63;
+/// The longest a whole name can be, once encoded
+const MAX_NAME_LEN: usize = 255;
+
+/// Reads the nameservers from a resolv.conf file. A missing file has none.
+pub fn read_nameservers(path: &Path) -> Vec<IpAddr> {
+    let contents = match fs::read_to_string(path) {
+        Ok(c) => c,
+        Err(e) => {
+            log::debug!("couldn't read {path:?}: {e}");
+            return Vec::new();
+        }
+    };
+
+    contents
+        .lines()
+        .filter_map(|line| {
+            let mut words = line.split_whitespace();
+            match words.next() {
+                Some("nameserver") => words.next(),
+                _ => None,
+            }
+        })
+        // Drop any scope, e.g. fe80::1%en0
+        .filter_map(|ns| ns.split('%').next()?.parse().ok())
+        .collect()
+}
+
+/// Whether a nameserver counts. One on loopback is only useful if it's
+/// systemd-resolved's stub, and one in an excluded range (e.g. on a container
+/// bridge) doesn't count either. Link-local ones are fine, as routers often
+/// hand those out.
+pub fn is_usable(policy: &AddressPolicy, ns: &IpAddr) -> bool {
+    *ns == RESOLVED_STUB || !(ns.is_loopback() || policy.is_excluded(*ns))
+}
+
+/// `query=` and `server=` options for `dns-ready`.
+#[derive(Clone, Debug, Default)]
+pub struct DnsOptions {
+    /// Name to look up to check that DNS answers
+    pub query: Option<String>,
+    /// Server to ask instead of the first configured nameserver
+    pub server: Option<SocketAddr>,
+}
+
+impl DnsOptions {
+    /// Applies a `key=value` option.
+    pub fn qualify(&mut self, key: &str, value: &str) -> Result<(), String> {
+        match key {
+            "query" => {
+                build_query(0, value)?;
+                self.query = Some(value.to_string());
+            }
+            "server" => {
+                let server = match value.parse::<IpAddr>() {
+                    Ok(ip) => SocketAddr::new(ip, 53),
+                    Err(_) => value
+                        .parse()
+                        .map_err(|_| format!("invalid server '{value}'"))?,
+                };
+                self.server = Some(server);
+            }
+            _ => return Err(format!("unknown option '{key}' (expected query or server)")),
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for DnsOptions {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let Some(query) = &self.query {
+            write!(f, ",query={query}")?;
+        }
+        if let Some(server) = self.server {
+            write!(f, ",server={server}")?;
+        }
+        Ok(())
+    }
+}
+
+/// The `dns-ready` condition, along with its probe's progress.
+#[derive(Debug)]
+pub struct DnsCheck {
+    pub options: DnsOptions,
+    probe: Probe<()>,
+    answered: bool,
+}
+
+impl DnsCheck {
+    pub fn new(options: DnsOptions) -> Self {
+        Self {
+            options,
+            probe: Probe::new(QUERY_TIMEOUT),
+            answered: false,
+        }
+    }
+
+    pub fn holds(&mut self, state: &NetworkState) -> bool {
+        let configured = state
+            .nameservers()
+            .iter()
+            .find(|ns| is_usable(state.policy(), ns));
+        log::trace!("nameserver configured? {configured:?}");
+        let (name, server) = match (&self.options.query, self.options.server, configured) {
+            (None, _, configured) => return configured.is_some(),
+            (Some(name), Some(server), _) => (name, server),
+            (Some(name), None, Some(ns)) => (name, SocketAddr::new(*ns, 53)),
+            (Some(_), None, None) => return false,
+        };
+
+        if let Some(result) = self.probe.finished() {
+            self.answered = match result {
+                Ok(()) => true,
+                Err(e) => {
+                    log::debug!("no answer for {name} from {server}: {e}");
+                    false
+                }
+            };
+            self.probe.record(self.answered);
+        }
+        if self.probe.is_due() {
+            let name = name.clone();
+            self.probe
+                .start(move |deadline| query(server, &name, deadline));
+        }
+        self.answered
+    }
+
+    /// How long until the probe should be checked on, if there is one.
+    pub fn next_probe_in(&self) -> Option<Duration> {
+        self.options.query.as_ref().and(self.probe.next_check_in())
+    }
+
+    pub fn network_changed(&mut self) {
+        self.probe.network_changed();
+    }
+}
+
+/// Builds an `A` query for `name`, which has to be a valid domain name.
+fn build_query(id: u16, name: &str) -> Result<Vec<u8>, String> {
+    let mut msg = Vec::with_capacity(name.len() + 18);
+    msg.extend_from_slice(&id.to_be_bytes());
+    // Recursion desired
+    msg.extend_from_slice(&0x0100u16.to_be_bytes());
+    // One question, no answer, authority or additional records
+    msg.extend_from_slice(&[0, 1, 0, 0, 0, 0, 0, 0]);
+
+    let start = msg.len();
+    for label in name.strip_suffix('.').unwrap_or(name).split('.') {
+        if !(1..=MAX_LABEL_LEN).contains(&label.len()) {
+            return Err(format!(
+                "invalid name '{name}' (labels must be 1 to {MAX_LABEL_LEN} bytes)"
+            ));
+        }
+        msg.push(label.len() as u8);
+        msg.extend_from_slice(label.as_bytes());
+    }
+    msg.push(0);
+    if msg.len() - start > MAX_NAME_LEN {
+        return Err(format!(
+            "invalid name '{name}' (longer than {MAX_NAME_LEN} bytes)"
+        ));
+    }
+
+    // QTYPE A, QCLASS IN
+    msg.extend_from_slice(&[0, 1, 0, 1]);
+    Ok(msg)
+}
+
+/// Asks `server` about `name`, and succeeds if it gives at least one answer
+/// by `deadline`.
+fn query(server: SocketAddr, name: &str, deadline: Instant) -> io::Result<()> {
+    let bind: SocketAddr = match server {
+        SocketAddr::V4(_) => (Ipv4Addr::UNSPECIFIED, 0).into(),
+        SocketAddr::V6(_) => (Ipv6Addr::UNSPECIFIED, 0).into(),
+    };
+    let socket = UdpSocket::bind(bind)?;
+
+    // Not for security, just so we can pick out our answer
+    let nanos = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map(|d| d.subsec_nanos())
+        .unwrap_or(0);
+    let id = (std::process::id() as u16) ^ (nanos as u16);
+    let msg = build_query(id, name).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+    socket.send_to(&msg, server)?;
+
+    let mut buf = [0u8; 512];
+    loop {
+        let left = deadline.saturating_duration_since(Instant::now());
+        if left.is_zero() {
+            return Err(io::ErrorKind::TimedOut.into());
+        }
+        socket.set_read_timeout(Some(left))?;
+
+        let (n, from) = match socket.recv_from(&mut buf) {
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                return Err(io::ErrorKind::TimedOut.into())
+            }
+            r => r?,
+        };
+        if from != server || n < 12 || buf[..2] != id.to_be_bytes() || buf[2] & 0x80 == 0 {
+            continue;
+        }
+
+        let rcode = buf[3] & 0x0f;
+        let answers = u16::from_be_bytes([buf[6], buf[7]]);
+        return match (rcode, answers) {
+            (0, 1..) => Ok(()),
+            (0, 0) => Err(io::Error::new(io::ErrorKind::NotFound, "no answers")),
+            (rcode, _) => Err(io::Error::other(format!(
+                "server responded with rcode {rcode}"
+            ))),
+        };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+
+    use super::*;
+
+    #[test]
+    fn usable_nameservers() {
+        let policy = AddressPolicy {
+            exclude: vec!["172.17.0.0/16".parse().unwrap()],
+            ..Default::default()
+        };
+        let usable = |ns: &str| is_usable(&policy, &ns.parse().unwrap());
+        assert!(usable("192.168.1.1"));
+        assert!(usable("fe80::1"));
+        assert!(usable("127.0.0.53"));
+        assert!(!usable("127.0.0.1"));
+        assert!(!usable("::1"));
+        assert!(!usable("172.17.0.1"));
+    }
+
+    #[test]
+    fn queries() {
+        let msg = build_query(0x1234, "example.com.").unwrap();
+        assert_eq!(&msg[..4], &[0x12, 0x34, 0x01, 0x00]);
+        assert_eq!(&msg[4..12], &[0, 1, 0, 0, 0, 0, 0, 0]);
+        assert_eq!(&msg[12..], b"\x07example\x03com\x00\x00\x01\x00\x01");
+        // The trailing dot is optional
+        assert_eq!(build_query(0x1234, "example.com"), Ok(msg));
+    }
+
+    #[test]
+    fn bad_names() {
+        assert!(build_query(0, "").is_err());
+        assert!(build_query(0, "example..com").is_err());
+        assert!(build_query(0, ".example.com").is_err());
+        assert!(build_query(0, &"a".repeat(63)).is_ok());
+        assert!(build_query(0, &"a".repeat(64)).is_err());
+
+        // 4 labels of 63 encode to 257 bytes, 3 and a bit fit in 255
+        let label = "a".repeat(63);
+        let fits = format!("{label}.{label}.{label}.{}", "a".repeat(61));
+        assert!(build_query(0, &fits).is_ok());
+        assert!(build_query(0, &format!("{fits}a")).is_err());
+    }
+
+    /// Answers one query on loopback, with `rcode` and `answers` answers.
+    fn stand_in(rcode: u8, answers: u8) -> SocketAddr {
+        let socket = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let addr = socket.local_addr().unwrap();
+        thread::spawn(move || {
+            let mut buf = [0u8; 512];
+            let (n, from) = socket.recv_from(&mut buf).unwrap();
+            // Echo the question back with the answer flag and counts set
+            buf[2] |= 0x80;
+            buf[3] = rcode;
+            buf[7] = answers;
+            socket.send_to(&buf[..n], from).unwrap();
+        });
+        addr
+    }
+
+    fn deadline() -> Instant {
+        Instant::now() + Duration::from_secs(1)
+    }
+
+    #[test]
+    fn answered() {
+        let server = stand_in(0, 1);
+        query(server, "example.com", deadline()).unwrap();
+    }
+
+    #[test]
+    fn not_answered() {
+        let server = stand_in(0, 0);
+        let err = query(server, "example.com", deadline()).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::NotFound);
+
+        // NXDOMAIN
+        let server = stand_in(3, 0);
+        assert!(query(server, "example.com", deadline()).is_err());
+    }
+
+    #[test]
+    fn times_out() {
+        // Never answers
+        let silent = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let server = silent.local_addr().unwrap();
+
+        let start = Instant::now();
+        let deadline = start + Duration::from_millis(100);
+        let err = query(server, "example.com", deadline).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+        assert!(start.elapsed() < Duration::from_secs(1));
+    }
+}
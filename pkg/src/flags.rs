@@ -1,13 +1,31 @@
+use std::fmt;
+use std::net::{IpAddr, SocketAddr};
 use std::str::FromStr;
+use std::time::Duration;
 
-use clap::Parser;
+use clap::{Parser, Subcommand};
+use ipnetwork::IpNetwork;
 use lazy_static::lazy_static;
 
+use crate::address::{AddressFilter, AddressScope};
+use crate::dns::DnsOptions;
+use crate::gateway::GatewayFilter;
+use crate::http::HttpOptions;
+use crate::interface::InterfaceSpec;
+use crate::parser;
+use crate::ping::PingOptions;
+use crate::probe::Comparison;
+use crate::tcp::TcpOptions;
+
 lazy_static! {
-    static ref FLAG_VARIANTS: [WaitConditionFlag; 3] = [
-        WaitConditionFlag::DefaultRouteExists,
-        WaitConditionFlag::InterfaceHasRoute("en0".to_string()),
-        WaitConditionFlag::InterfaceHasAddress("en0".to_string()),
+    static ref FLAG_VARIANTS: [WaitConditionFlag; 4] = [
+        WaitConditionFlag::DefaultRouteExists(GatewayFilter::default()),
+        WaitConditionFlag::GatewayReachable(GatewayFilter::default()),
+        WaitConditionFlag::InterfaceHasRoute(InterfaceSpec::Name("en0".to_string())),
+        WaitConditionFlag::InterfaceHasAddress(
+            InterfaceSpec::Name("en0".to_string()),
+            AddressFilter::default()
+        ),
     ];
 }
 
@@ -15,9 +33,39 @@ lazy_static! {
 #[derive(Parser)]
 pub struct Args {
     /// Specifes the exit condition:
+    /// - A local address can be bound to (can-bind=<192.0.2.10:443> or can-bind=<[2001:db8::5]:443>)
     /// - A global default route is available (default-route)
+    ///   (optionally via a given gateway: default-route,gateway=<192.168.1.1> or gateway-mac=<aa:bb:..>)
+    /// - The default route's gateway has a resolved link-layer address (gateway-reachable)
+    ///   (which takes the same gateway options)
+    /// - A non-loopback nameserver is configured (dns-ready)
+    ///   (optionally also answering a query: dns-ready,query=<example.com>[,server=<ip[:port]>])
+    /// - An HTTP server answers directly, not through a captive portal (http=<http://host/path>)
+    ///   (expecting a 204 unless given: http=<url>,expect=200[,body=<text>])
     /// - A specific interface receives a non-link-local address (if-gets-address=<eth0>)
+    ///   (optionally of a given family and scope: if-gets-address=<eth0>,family=inet6,scope=global)
     /// - A specific interface receives a non-local route (if-gets-route=<eth0>)
+    /// - A specific interface's link is up and running (if-link-up=<eth0>)
+    /// - Any interface holds an address within a subnet (has-address=<10.20.0.0/16>)
+    /// - A host answers pings (ping=<10.0.0.1>)
+    ///   (optionally with a number of pings and a limit on loss: ping=<10.0.0.1>,count=3,loss<=33%)
+    /// - A destination is routable (route-to=<10.50.3.4>)
+    ///   (optionally through given interfaces: route-to=<10.50.3.4>,via=utun*)
+    /// - The kernel would pick a usable source address for a destination (routable=<8.8.8.8>)
+    ///   (optionally of a given scope: routable=<8.8.8.8>,src-scope=global)
+    /// - A TCP service accepts connections (tcp=<10.0.0.5:5432>)
+    ///   (optionally with a timeout for each attempt: tcp=<db:5432>,timeout=2s)
+    ///
+    /// http, ping and tcp also take limits on the median round trip time and
+    /// loss over their last few attempts, e.g. ping=<10.0.0.1>,rtt<50ms,loss<5%,window=20
+    ///
+    /// Interfaces can also be given as patterns (utun*, re:^en[0-9]+$), which
+    /// any matching interface satisfies, or as mac:<aa:bb:cc:dd:ee:ff> or index:<7>.
+    ///
+    /// Conditions can be combined with all(...), any(...) and not(...), e.g.
+    /// all(default-route, any(if-gets-address=en0, if-gets-address=en1))
+    /// or with quorum(K, ...) for at least K of them, e.g.
+    /// quorum(2, tcp=a:443, tcp=b:443, ping=<c>)
 
     #[arg(
         short,
@@ -28,25 +76,289 @@ pub struct Args {
     )]
     pub wait_condition: WaitConditionFlag,
 
+    /// Wait for the condition to stop holding instead, e.g. for the default
+    /// route to disappear. The same as wrapping it in not(...).
+    #[arg(long, env = "NETAWAIT_UNTIL_LOST")]
+    pub until_lost: bool,
+
+    /// Only exit once the condition has held for this long (e.g. 5s or
+    /// 500ms). If it stops holding in the meantime, the wait starts over.
+    #[arg(long, env = "NETAWAIT_STABLE_FOR", value_parser = parse_duration)]
+    pub stable_for: Option<Duration>,
+
     /// If specified, will only wait this long for our condition to be met.
     #[arg(short, long, env = "NETAWAIT_TIMEOUT")]
     pub timeout: Option<i32>,
 
+    /// Print the event which satisfied the wait condition to stdout, as JSON
+    #[arg(long, env = "NETAWAIT_JSON")]
+    pub json: bool,
+
+    /// Print what satisfied the wait condition (e.g. which interface matched)
+    /// to stdout. With --json, this is printed as JSON after the event.
+    #[arg(long, env = "NETAWAIT_REPORT")]
+    pub report: bool,
+
+    /// Don't count addresses and routes within this range, e.g. a container
+    /// or VM bridge. Can be given more than once.
+    #[arg(
+        long = "exclude-range",
+        value_name = "CIDR",
+        env = "NETAWAIT_EXCLUDE_RANGE",
+        value_delimiter = ','
+    )]
+    pub exclude_ranges: Vec<IpNetwork>,
+
+    /// Count addresses and routes within this range, even if they're inside
+    /// an excluded range or link-local. The most specific range wins. Can be
+    /// given more than once.
+    #[arg(
+        long = "include-range",
+        value_name = "CIDR",
+        env = "NETAWAIT_INCLUDE_RANGE",
+        value_delimiter = ','
+    )]
+    pub include_ranges: Vec<IpNetwork>,
+
+    /// Count link-local addresses and routes, e.g. for hosts connected
+    /// directly by a cable
+    #[arg(long, env = "NETAWAIT_ALLOW_LINK_LOCAL")]
+    pub allow_link_local: bool,
+
     /// Log level to display output at
     #[arg(short, long, env = "NETAWAIT_LOG_LEVEL", default_value = "warn")]
     pub log_level: log::LevelFilter,
+
+    #[command(subcommand)]
+    pub command: Option<Command>,
 }
 
-#[derive(Clone)]
+#[derive(Subcommand)]
+pub enum Command {
+    /// Show which route the kernel would use to reach a destination, instead
+    /// of waiting
+    RouteGet { destination: IpAddr },
+}
+
+/// Parses a duration such as `5s`, `500ms` or `2m`. Plain numbers are
+/// seconds.
+pub fn parse_duration(s: &str) -> Result<Duration, String> {
+    let split = s
+        .find(|c: char| !(c.is_ascii_digit() || c == '.'))
+        .unwrap_or(s.len());
+    let (number, unit) = s.split_at(split);
+    let number: f64 = number
+        .parse()
+        .map_err(|_| format!("invalid duration '{s}'"))?;
+
+    let secs = match unit {
+        "ms" => number / 1000.0,
+        "" | "s" => number,
+        "m" => number * 60.0,
+        _ => return Err(format!("invalid unit in '{s}' (expected ms, s or m)")),
+    };
+    Ok(Duration::from_secs_f64(secs))
+}
+
+/// Names of all the (non-combinator) wait conditions.
+pub const CONDITION_NAMES: &[&str] = &[
+    "can-bind",
+    "default-route",
+    "dns-ready",
+    "gateway-reachable",
+    "has-address",
+    "http",
+    "if-gets-address",
+    "if-gets-route",
+    "if-link-up",
+    "ping",
+    "routable",
+    "route-to",
+    "tcp",
+];
+
+#[derive(Clone, Debug)]
 pub enum WaitConditionFlag {
-    DefaultRouteExists,
-    InterfaceHasAddress(String),
-    InterfaceHasRoute(String),
+    All(Vec<WaitConditionFlag>),
+    Any(Vec<WaitConditionFlag>),
+    Not(Box<WaitConditionFlag>),
+    /// How many of the conditions must hold
+    Quorum(usize, Vec<WaitConditionFlag>),
+
+    /// Address to bind, with port 0 if none was given
+    CanBind(SocketAddr),
+    DefaultRouteExists(GatewayFilter),
+    GatewayReachable(GatewayFilter),
+    DnsReady(DnsOptions),
+    HasAddress(IpNetwork),
+    HttpOnline(HttpOptions),
+    InterfaceHasAddress(InterfaceSpec, AddressFilter),
+    InterfaceHasRoute(InterfaceSpec),
+    InterfaceLinkUp(InterfaceSpec),
+    Ping(PingOptions),
+    /// Destination, and optionally the interface to route via
+    RouteTo(IpAddr, Option<InterfaceSpec>),
+    /// Destination, and optionally the scope the source address must have
+    Routable(IpAddr, Option<AddressScope>),
+    TcpConnect(TcpOptions),
 }
 
 impl Default for WaitConditionFlag {
     fn default() -> Self {
-        Self::DefaultRouteExists
+        Self::DefaultRouteExists(GatewayFilter::default())
+    }
+}
+
+impl WaitConditionFlag {
+    /// Builds a single condition from its name and value, e.g.
+    /// `if-gets-address` and `en0`.
+    pub fn from_parts(name: &str, value: Option<&str>) -> Result<Self, String> {
+        match (name, value) {
+            ("default-route", None) => Ok(Self::DefaultRouteExists(GatewayFilter::default())),
+            ("gateway-reachable", None) => Ok(Self::GatewayReachable(GatewayFilter::default())),
+            ("dns-ready", None) => Ok(Self::DnsReady(DnsOptions::default())),
+            ("default-route" | "gateway-reachable" | "dns-ready", Some(_)) => {
+                Err(format!("{name} doesn't take a value"))
+            }
+            ("can-bind", Some(addr)) => match addr.parse::<IpAddr>() {
+                Ok(ip) => Ok(Self::CanBind(SocketAddr::new(ip, 0))),
+                Err(_) => addr
+                    .parse()
+                    .map(Self::CanBind)
+                    .map_err(|e| format!("invalid address '{addr}': {e}")),
+            },
+            ("has-address", Some(cidr)) => cidr
+                .parse()
+                .map(Self::HasAddress)
+                .map_err(|e| format!("invalid address or subnet '{cidr}': {e}")),
+            ("if-gets-address", Some(spec)) => Ok(Self::InterfaceHasAddress(
+                spec.parse()?,
+                AddressFilter::default(),
+            )),
+            ("if-gets-route", Some(spec)) => Ok(Self::InterfaceHasRoute(spec.parse()?)),
+            ("if-link-up", Some(spec)) => Ok(Self::InterfaceLinkUp(spec.parse()?)),
+            ("route-to", Some(ip)) => ip
+                .parse()
+                .map(|ip| Self::RouteTo(ip, None))
+                .map_err(|e| format!("invalid address '{ip}': {e}")),
+            ("routable", Some(ip)) => ip
+                .parse()
+                .map(|ip| Self::Routable(ip, None))
+                .map_err(|e| format!("invalid address '{ip}': {e}")),
+            ("http", Some(url)) => HttpOptions::new(url).map(Self::HttpOnline),
+            ("http", None) => Err(format!("missing URL value for {name}")),
+            ("ping", Some(ip)) => ip
+                .parse()
+                .map(|ip| Self::Ping(PingOptions::new(ip)))
+                .map_err(|e| format!("invalid address '{ip}': {e}")),
+            ("tcp", Some(target)) => TcpOptions::new(target).map(Self::TcpConnect),
+            ("tcp", None) => Err(format!("missing host:port value for {name}")),
+            ("can-bind" | "has-address" | "ping" | "routable" | "route-to", None) => {
+                Err(format!("missing address value for {name}"))
+            }
+            (_, None) => Err(format!("missing interface value for {name}")),
+            (_, Some(_)) => Err(format!("unknown wait condition '{name}'")),
+        }
+    }
+
+    /// Applies a `key=value` option given after a condition, e.g. the
+    /// `family=inet6` in `if-gets-address=en0,family=inet6`.
+    pub fn qualify(&mut self, key: &str, value: &str) -> Result<(), String> {
+        match self {
+            Self::DefaultRouteExists(filter) | Self::GatewayReachable(filter) => {
+                filter.qualify(key, value)
+            }
+            Self::DnsReady(options) => options.qualify(key, value),
+            Self::HttpOnline(options) => options.qualify(key, value),
+            Self::InterfaceHasAddress(_, filter) => filter.qualify(key, value),
+            Self::Ping(options) => options.qualify(key, value),
+            Self::TcpConnect(options) => options.qualify(key, value),
+            Self::RouteTo(_, via) => match key {
+                "via" => {
+                    *via = Some(value.parse()?);
+                    Ok(())
+                }
+                _ => Err(format!("unknown option '{key}' (expected via)")),
+            },
+            Self::Routable(_, scope) => match key {
+                "src-scope" => {
+                    *scope = Some(value.parse()?);
+                    Ok(())
+                }
+                _ => Err(format!("unknown option '{key}' (expected src-scope)")),
+            },
+            _ => Err(format!("{} doesn't take any options", self.name())),
+        }
+    }
+
+    /// Applies a `key<value` or `key<=value` limit given after a condition,
+    /// e.g. the `loss<=33%` in `ping=10.0.0.1,loss<=33%`.
+    pub fn limit(&mut self, key: &str, comparison: Comparison, value: &str) -> Result<(), String> {
+        match self {
+            Self::HttpOnline(options) => options.limit(key, comparison, value),
+            Self::Ping(options) => options.limit(key, comparison, value),
+            Self::TcpConnect(options) => options.limit(key, comparison, value),
+            _ => Err(format!("{} doesn't take any limits", self.name())),
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        match self {
+            Self::All(_) => "all",
+            Self::Any(_) => "any",
+            Self::Not(_) => "not",
+            Self::Quorum(..) => "quorum",
+            Self::CanBind(_) => "can-bind",
+            Self::DefaultRouteExists(_) => "default-route",
+            Self::GatewayReachable(_) => "gateway-reachable",
+            Self::DnsReady(_) => "dns-ready",
+            Self::HasAddress(_) => "has-address",
+            Self::HttpOnline(_) => "http",
+            Self::InterfaceHasAddress(..) => "if-gets-address",
+            Self::InterfaceHasRoute(_) => "if-gets-route",
+            Self::InterfaceLinkUp(_) => "if-link-up",
+            Self::Ping(_) => "ping",
+            Self::RouteTo(..) => "route-to",
+            Self::Routable(..) => "routable",
+            Self::TcpConnect(_) => "tcp",
+        }
+    }
+}
+
+fn write_list(f: &mut fmt::Formatter<'_>, name: &str, conds: &[WaitConditionFlag]) -> fmt::Result {
+    let strs: Vec<String> = conds.iter().map(|c| c.to_string()).collect();
+    write!(f, "{name}({})", strs.join(", "))
+}
+
+impl fmt::Display for WaitConditionFlag {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::All(conds) => write_list(f, "all", conds),
+            Self::Any(conds) => write_list(f, "any", conds),
+            Self::Not(cond) => write!(f, "not({cond})"),
+            Self::Quorum(needed, conds) => {
+                let strs: Vec<String> = conds.iter().map(|c| c.to_string()).collect();
+                write!(f, "quorum({needed}, {})", strs.join(", "))
+            }
+            Self::CanBind(addr) if addr.port() == 0 => write!(f, "can-bind={}", addr.ip()),
+            Self::CanBind(addr) => write!(f, "can-bind={addr}"),
+            Self::DefaultRouteExists(filter) => write!(f, "default-route{filter}"),
+            Self::GatewayReachable(filter) => write!(f, "gateway-reachable{filter}"),
+            Self::DnsReady(options) => write!(f, "dns-ready{options}"),
+            Self::HasAddress(net) => write!(f, "has-address={net}"),
+            Self::HttpOnline(options) => write!(f, "http={options}"),
+            Self::InterfaceHasAddress(spec, filter) => {
+                write!(f, "if-gets-address={spec}{filter}")
+            }
+            Self::InterfaceHasRoute(spec) => write!(f, "if-gets-route={spec}"),
+            Self::InterfaceLinkUp(spec) => write!(f, "if-link-up={spec}"),
+            Self::Ping(options) => write!(f, "ping={options}"),
+            Self::RouteTo(ip, None) => write!(f, "route-to={ip}"),
+            Self::RouteTo(ip, Some(via)) => write!(f, "route-to={ip},via={via}"),
+            Self::Routable(ip, None) => write!(f, "routable={ip}"),
+            Self::Routable(ip, Some(scope)) => write!(f, "routable={ip},src-scope={scope}"),
+            Self::TcpConnect(options) => write!(f, "tcp={options}"),
+        }
     }
 }
 
@@ -54,23 +366,29 @@ impl FromStr for WaitConditionFlag {
     type Err = String;
 
     fn from_str(input: &str) -> Result<Self, Self::Err> {
-        let mut parts = input.split_terminator(&['=', ' ', ':']);
-        let key = parts.next();
-        match key {
-            Some("default-route") => Ok(Self::DefaultRouteExists),
-            Some("if-gets-address") | Some("if-gets-route") => {
-                let if_name = parts
-                    .next()
-                    .ok_or("missing interface value for wait condition")?
-                    .to_string();
-                Ok(match key.unwrap() {
-                    "if-gets-address" => WaitConditionFlag::InterfaceHasAddress(if_name),
-                    "if-gets-route" => WaitConditionFlag::InterfaceHasRoute(if_name),
-                    _ => unreachable!(),
-                })
-            }
-            Some(s) => Err(format!("invalid value for wait condition: {s}")),
-            None => Err("missing value for wait condition".to_string()),
-        }
+        parser::parse(input).map_err(|e| e.to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn durations() {
+        assert_eq!(parse_duration("5"), Ok(Duration::from_secs(5)));
+        assert_eq!(parse_duration("5s"), Ok(Duration::from_secs(5)));
+        assert_eq!(parse_duration("1.5s"), Ok(Duration::from_millis(1500)));
+        assert_eq!(parse_duration("500ms"), Ok(Duration::from_millis(500)));
+        assert_eq!(parse_duration("2m"), Ok(Duration::from_secs(120)));
+    }
+
+    #[test]
+    fn bad_durations() {
+        assert!(parse_duration("").is_err());
+        assert!(parse_duration("s").is_err());
+        assert!(parse_duration("5h").is_err());
+        assert!(parse_duration("1.2.3s").is_err());
+        assert!(parse_duration("-5s").is_err());
     }
 }
@@ -0,0 +1,91 @@
+//! Picking out default routes by their gateway.
+
+use std::fmt;
+use std::net::IpAddr;
+
+use libroute::addresses::{format_mac, SockAddr};
+use libroute::table::Route;
+
+use crate::interface::parse_mac;
+use crate::state::NetworkState;
+
+/// Narrows down which default routes satisfy a condition, from `gateway=`
+/// and `gateway-mac=` options.
+#[derive(Clone, Debug, Default)]
+pub struct GatewayFilter {
+    pub ip: Option<IpAddr>,
+    pub mac: Option<Vec<u8>>,
+}
+
+impl GatewayFilter {
+    /// Applies a `key=value` option to this filter.
+    pub fn qualify(&mut self, key: &str, value: &str) -> Result<(), String> {
+        match key {
+            "gateway" => {
+                let ip = value
+                    .parse()
+                    .map_err(|e| format!("invalid gateway '{value}': {e}"))?;
+                self.ip = Some(ip);
+            }
+            "gateway-mac" => self.mac = Some(parse_mac(value)?),
+            _ => {
+                return Err(format!(
+                    "unknown option '{key}' (expected gateway or gateway-mac)"
+                ))
+            }
+        }
+        Ok(())
+    }
+
+    pub fn matches(&self, state: &NetworkState, route: &Route) -> bool {
+        let gateway = match &route.gateway {
+            Some(gw) => gw,
+            None => return false,
+        };
+
+        if let Some(want) = self.ip {
+            if gateway.ip() != Some(want) {
+                return false;
+            }
+        }
+
+        match &self.mac {
+            Some(want) => gateway_mac(state, gateway) == Some(want),
+            None => true,
+        }
+    }
+}
+
+/// The link-layer address of a gateway: either given directly (for routes
+/// straight to a link-layer address), or from its neighbour entry.
+fn gateway_mac<'a>(state: &'a NetworkState, gateway: &'a SockAddr) -> Option<&'a Vec<u8>> {
+    match gateway {
+        SockAddr::Link(l) => Some(&l.link_layer_addr),
+        gw => {
+            let neighbor = state.neighbors().get(&gw.ip()?)?;
+            neighbor.is_resolved().then_some(&neighbor.link_layer_addr)
+        }
+    }
+}
+
+/// Describes a gateway for the report, e.g. `192.168.1.1` or
+/// `aa:bb:cc:dd:ee:ff`.
+pub fn describe(gateway: &SockAddr) -> String {
+    match gateway {
+        SockAddr::Link(l) if l.link_layer_addr.is_empty() => format!("index:{}", l.index),
+        SockAddr::Link(l) => format_mac(&l.link_layer_addr),
+        gw => gw.ip().map(|ip| ip.to_string()).unwrap_or_default(),
+    }
+}
+
+impl fmt::Display for GatewayFilter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let Some(ip) = self.ip {
+            write!(f, ",gateway={ip}")?;
+        }
+        if let Some(mac) = &self.mac {
+            write!(f, ",gateway-mac={}", format_mac(mac))?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,434 @@
+//! Checking that the internet is actually reachable over HTTP, rather than
+//! through a captive portal.
+
+use std::fmt;
+use std::io::{self, Read, Write};
+use std::net::{IpAddr, SocketAddr, TcpStream, ToSocketAddrs};
+use std::time::{Duration, Instant};
+
+use crate::flags::parse_duration;
+use crate::probe::{Comparison, Probe, Samples, Stats, Thresholds, DEFAULT_WINDOW};
+use crate::state::NetworkState;
+
+/// How long each request gets, unless given with `timeout=`
+const DEFAULT_REQUEST_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// The most we'll read of a response. Portal login pages can be big, but we
+/// only need enough to tell them apart from what we expected.
+const MAX_RESPONSE_LEN: u64 = 64 * 1024;
+
+/// `http=URL` and its options.
+#[derive(Clone, Debug)]
+pub struct HttpOptions {
+    pub url: String,
+    /// `host[:port]`, as given in the URL, for the `Host` header
+    authority: String,
+    host: String,
+    port: u16,
+    path: String,
+    /// The status code we get when we're online
+    pub expect: u16,
+    /// Text the body must contain when we're online
+    pub body: Option<String>,
+    /// How long each request gets, from looking up the host to reading the
+    /// response. This is separate from --timeout, which covers the whole wait.
+    pub timeout: Duration,
+    pub thresholds: Thresholds,
+}
+
+impl HttpOptions {
+    pub fn new(url: &str) -> Result<Self, String> {
+        if url.starts_with("https://") {
+            return Err("https URLs aren't supported, use an http:// one".to_string());
+        }
+        let rest = url
+            .strip_prefix("http://")
+            .ok_or_else(|| format!("invalid URL '{url}' (expected http://...)"))?;
+
+        let rest = rest.split('#').next().unwrap_or(rest);
+        let (authority, path) = match rest.find('/') {
+            Some(i) => rest.split_at(i),
+            None => (rest, "/"),
+        };
+
+        let (host, port) = match authority.strip_prefix('[') {
+            // IPv6, e.g. [2001:db8::1]:8080
+            Some(v6) => match v6.split_once(']') {
+                Some((host, "")) => (host, None),
+                Some((host, port)) => (host, port.strip_prefix(':')),
+                None => return Err(format!("invalid host in '{url}'")),
+            },
+            None => match authority.rsplit_once(':') {
+                Some((host, port)) => (host, Some(port)),
+                None => (authority, None),
+            },
+        };
+        if host.is_empty() {
+            return Err(format!("missing host in '{url}'"));
+        }
+        let port = match port {
+            Some(port) => port
+                .parse()
+                .map_err(|_| format!("invalid port in '{url}'"))?,
+            None => 80,
+        };
+
+        Ok(Self {
+            url: url.to_string(),
+            authority: authority.to_string(),
+            host: host.to_string(),
+            port,
+            path: path.to_string(),
+            expect: 204,
+            body: None,
+            timeout: DEFAULT_REQUEST_TIMEOUT,
+            thresholds: Thresholds::default(),
+        })
+    }
+
+    /// Applies a `key=value` option.
+    pub fn qualify(&mut self, key: &str, value: &str) -> Result<(), String> {
+        match key {
+            "expect" => {
+                self.expect = match value.parse() {
+                    Ok(code @ 100..=599) => code,
+                    _ => return Err(format!("invalid status code '{value}'")),
+                }
+            }
+            "body" => self.body = Some(value.to_string()),
+            "timeout" => self.timeout = parse_duration(value)?,
+            "window" => self.thresholds.set_window(value)?,
+            _ => {
+                return Err(format!(
+                    "unknown option '{key}' (expected expect, body, timeout or window)"
+                ))
+            }
+        }
+        Ok(())
+    }
+
+    /// Applies a `key<value` or `key<=value` limit.
+    pub fn limit(&mut self, key: &str, comparison: Comparison, value: &str) -> Result<(), String> {
+        self.thresholds.limit(key, comparison, value)
+    }
+}
+
+impl fmt::Display for HttpOptions {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.url)?;
+        if self.expect != 204 {
+            write!(f, ",expect={}", self.expect)?;
+        }
+        if let Some(body) = &self.body {
+            write!(f, ",body=\"{body}\"")?;
+        }
+        if self.timeout != DEFAULT_REQUEST_TIMEOUT {
+            write!(f, ",timeout={:?}", self.timeout)?;
+        }
+        write!(f, "{}", self.thresholds)
+    }
+}
+
+/// What we made of the last request.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Outcome {
+    /// We got the response we expected
+    Online,
+    /// Something answered, but not how the real server would have, e.g.
+    /// with a redirect to a login page
+    CaptivePortal(String),
+    /// We couldn't get a response at all
+    Unreachable(String),
+}
+
+/// The `http` condition, along with its probe's progress.
+#[derive(Debug)]
+pub struct HttpCheck {
+    pub options: HttpOptions,
+    /// What each request made of the response, and how long it took
+    probe: Probe<(Outcome, Duration)>,
+    samples: Samples,
+    outcome: Option<Outcome>,
+    passed: bool,
+}
+
+impl HttpCheck {
+    pub fn new(options: HttpOptions) -> Self {
+        let window = options.thresholds.window.unwrap_or(DEFAULT_WINDOW);
+        Self {
+            probe: Probe::new(options.timeout),
+            options,
+            samples: Samples::new(window),
+            outcome: None,
+            passed: false,
+        }
+    }
+
+    pub fn holds(&mut self, state: &NetworkState) -> bool {
+        // Without a route, the request can only fail, so wait for one rather
+        // than counting it as an attempt.
+        if let Ok(ip) = self.options.host.parse::<IpAddr>() {
+            if !state.is_routable(ip) {
+                log::trace!("no route to {ip} for {}", self.options.url);
+                return false;
+            }
+        }
+
+        if let Some(result) = self.probe.finished() {
+            let (outcome, rtt) = match result {
+                Ok((outcome, took)) => {
+                    let rtt = (outcome == Outcome::Online).then_some(took);
+                    (outcome, rtt)
+                }
+                Err(e) => (Outcome::Unreachable(e.to_string()), None),
+            };
+            match &outcome {
+                Outcome::Online => log::debug!("{} is online", self.options.url),
+                Outcome::CaptivePortal(why) if !self.is_captive() => {
+                    log::warn!("{} is behind a captive portal: {why}", self.options.url)
+                }
+                Outcome::CaptivePortal(why) => {
+                    log::debug!(
+                        "{} is still behind a captive portal: {why}",
+                        self.options.url
+                    )
+                }
+                Outcome::Unreachable(why) => {
+                    log::debug!("{} is unreachable: {why}", self.options.url)
+                }
+            }
+
+            self.samples.record(rtt);
+            self.passed = rtt.is_some() && self.options.thresholds.allow(&self.samples);
+            self.probe.record(self.passed);
+            self.outcome = Some(outcome);
+        }
+        if self.probe.is_due() {
+            let options = self.options.clone();
+            self.probe.start(move |deadline| {
+                let start = Instant::now();
+                let response = fetch(&options, deadline)?;
+                Ok((classify(&options, &response), start.elapsed()))
+            });
+        }
+        self.passed
+    }
+
+    /// Request times and loss over the window.
+    pub fn stats(&self) -> Stats {
+        self.samples.stats()
+    }
+
+    /// Whether the last request looked like it was answered by a captive
+    /// portal.
+    pub fn is_captive(&self) -> bool {
+        matches!(self.outcome, Some(Outcome::CaptivePortal(_)))
+    }
+
+    /// How long until the probe should be checked on.
+    pub fn next_probe_in(&self) -> Option<Duration> {
+        self.probe.next_check_in()
+    }
+
+    pub fn network_changed(&mut self) {
+        self.probe.network_changed();
+    }
+}
+
+struct Response {
+    status: u16,
+    location: Option<String>,
+    body: Vec<u8>,
+}
+
+fn classify(options: &HttpOptions, response: &Response) -> Outcome {
+    let status = response.status;
+    if status != options.expect {
+        let why = match status {
+            300..=399 => {
+                let to = response.location.as_deref().unwrap_or("nowhere");
+                format!("redirected ({status}) to {to}")
+            }
+            // Network Authentication Required, from RFC 6585
+            511 => "network authentication required (511)".to_string(),
+            200..=299 => format!("got status {status} instead of {}", options.expect),
+            _ => return Outcome::Unreachable(format!("server responded with status {status}")),
+        };
+        return Outcome::CaptivePortal(why);
+    }
+
+    let body = String::from_utf8_lossy(&response.body);
+    match &options.body {
+        Some(want) if !body.contains(want.as_str()) => {
+            Outcome::CaptivePortal(format!("body doesn't contain '{want}'"))
+        }
+        None if status == 204 && !body.trim().is_empty() => {
+            Outcome::CaptivePortal("got a body with a 204".to_string())
+        }
+        _ => Outcome::Online,
+    }
+}
+
+/// Makes a plain HTTP/1.0 GET request, so we don't have to deal with chunked
+/// responses or keep-alive. Everything from connecting to reading the
+/// response has to be done by `deadline`.
+fn fetch(options: &HttpOptions, deadline: Instant) -> io::Result<Response> {
+    let remaining = || {
+        let left = deadline.saturating_duration_since(Instant::now());
+        match left.is_zero() {
+            true => Err(io::Error::from(io::ErrorKind::TimedOut)),
+            false => Ok(left),
+        }
+    };
+
+    let addrs: Vec<SocketAddr> = (options.host.as_str(), options.port)
+        .to_socket_addrs()?
+        .collect();
+    let mut stream = None;
+    let mut last_err = io::Error::new(io::ErrorKind::NotFound, "no addresses found");
+    for addr in addrs {
+        match TcpStream::connect_timeout(&addr, remaining()?) {
+            Ok(s) => {
+                stream = Some(s);
+                break;
+            }
+            Err(e) => last_err = e,
+        }
+    }
+    let mut stream = stream.ok_or(last_err)?;
+
+    stream.set_write_timeout(Some(remaining()?))?;
+    write!(
+        stream,
+        "GET {} HTTP/1.0\r\nHost: {}\r\nUser-Agent: netawait\r\nAccept: */*\r\n\r\n",
+        options.path, options.authority
+    )?;
+
+    let mut data = Vec::new();
+    let mut buf = [0u8; 4096];
+    let mut reader = (&stream).take(MAX_RESPONSE_LEN);
+    loop {
+        stream.set_read_timeout(Some(remaining()?))?;
+        match reader.read(&mut buf)? {
+            0 => break,
+            n => data.extend_from_slice(&buf[..n]),
+        }
+    }
+
+    parse_response(&data)
+}
+
+fn parse_response(data: &[u8]) -> io::Result<Response> {
+    let invalid = || io::Error::new(io::ErrorKind::InvalidData, "invalid HTTP response");
+
+    let split = data
+        .windows(4)
+        .position(|w| w == b"\r\n\r\n")
+        .ok_or_else(invalid)?;
+    let head = String::from_utf8_lossy(&data[..split]);
+    let body = data[split + 4..].to_vec();
+
+    let mut lines = head.split("\r\n");
+    let status = lines
+        .next()
+        .and_then(|line| {
+            let mut words = line.split_whitespace();
+            match words.next() {
+                Some(version) if version.starts_with("HTTP/") => words.next()?.parse().ok(),
+                _ => None,
+            }
+        })
+        .ok_or_else(invalid)?;
+
+    let location = lines.find_map(|line| {
+        let (name, value) = line.split_once(':')?;
+        name.eq_ignore_ascii_case("location")
+            .then(|| value.trim().to_string())
+    });
+
+    Ok(Response {
+        status,
+        location,
+        body,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn outcome(options: &HttpOptions, data: &[u8]) -> Outcome {
+        classify(options, &parse_response(data).unwrap())
+    }
+
+    fn portal(why: &str) -> Outcome {
+        Outcome::CaptivePortal(why.to_string())
+    }
+
+    #[test]
+    fn no_content() {
+        let options = HttpOptions::new("http://example.com/generate_204").unwrap();
+        let online = b"HTTP/1.1 204 No Content\r\nContent-Length: 0\r\n\r\n";
+        assert_eq!(outcome(&options, online), Outcome::Online);
+
+        let with_body = b"HTTP/1.1 204 No Content\r\n\r\n<html>Log in</html>";
+        assert_eq!(
+            outcome(&options, with_body),
+            portal("got a body with a 204")
+        );
+    }
+
+    #[test]
+    fn portal_pages() {
+        let options = HttpOptions::new("http://example.com/").unwrap();
+        let page = b"HTTP/1.1 200 OK\r\nContent-Type: text/html\r\n\r\n<html>Log in</html>";
+        assert_eq!(
+            outcome(&options, page),
+            portal("got status 200 instead of 204")
+        );
+
+        let redirect = b"HTTP/1.1 302 Found\r\nlocation: http://login.example/\r\n\r\n";
+        assert_eq!(
+            outcome(&options, redirect),
+            portal("redirected (302) to http://login.example/")
+        );
+
+        let auth = b"HTTP/1.1 511 Network Authentication Required\r\n\r\n";
+        assert_eq!(
+            outcome(&options, auth),
+            portal("network authentication required (511)")
+        );
+
+        let broken = b"HTTP/1.1 503 Service Unavailable\r\n\r\n";
+        assert!(matches!(outcome(&options, broken), Outcome::Unreachable(_)));
+    }
+
+    #[test]
+    fn expected_body() {
+        let mut options = HttpOptions::new("http://example.com/hotspot.txt").unwrap();
+        options.qualify("expect", "200").unwrap();
+        options.qualify("body", "Success").unwrap();
+
+        let online = b"HTTP/1.0 200 OK\r\n\r\n<HTML>Success</HTML>";
+        assert_eq!(outcome(&options, online), Outcome::Online);
+
+        let page = b"HTTP/1.0 200 OK\r\n\r\n<html>Log in</html>";
+        assert_eq!(
+            outcome(&options, page),
+            portal("body doesn't contain 'Success'")
+        );
+    }
+
+    #[test]
+    fn truncated_responses() {
+        for data in [
+            &b""[..],
+            b"HTTP/1.1 204 No Content\r\nContent-",
+            b"<html>Log in</html>\r\n\r\n",
+            b"HTTP/1.1 \r\n\r\n",
+        ] {
+            let err = parse_response(data).err().unwrap();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidData, "parsing {data:?}");
+        }
+    }
+}
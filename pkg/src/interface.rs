@@ -0,0 +1,184 @@
+//! Selecting interfaces by name, pattern, MAC address or index.
+//!
+//! Linux interface aliases and altnames (`ip link property add altname`)
+//! aren't accepted, as there's nothing like them on macOS, the only platform
+//! netawait reads interfaces on.
+
+use std::fmt;
+use std::str::FromStr;
+
+use libroute::addresses::format_mac;
+use regex::Regex;
+
+use crate::state::NetworkState;
+
+#[derive(Clone, Debug)]
+pub enum InterfaceSpec {
+    Name(String),
+    /// Shell-style pattern, e.g. `utun*`
+    Glob(String),
+    /// `re:^en[0-9]+$`
+    Regex(Regex),
+    /// `mac:aa:bb:cc:dd:ee:ff`, for when we know the hardware but not what
+    /// it will be called
+    Mac(Vec<u8>),
+    /// `index:7`
+    Index(u16),
+}
+
+impl InterfaceSpec {
+    /// Whether the interface with the given index is the one we want.
+    pub fn matches(&self, state: &NetworkState, index: u16) -> bool {
+        if let Self::Index(want) = self {
+            return index == *want;
+        }
+
+        let link = match state.links().find(|l| l.index == index) {
+            Some(link) => link,
+            None => return false,
+        };
+        match (self, link.name.as_deref()) {
+            (Self::Name(want), Some(name)) => name == want,
+            (Self::Glob(pattern), Some(name)) => glob_matches(pattern, name),
+            (Self::Regex(re), Some(name)) => re.is_match(name),
+            (Self::Mac(want), _) => link.mac.as_ref() == Some(want),
+            _ => false,
+        }
+    }
+}
+
+pub fn parse_mac(s: &str) -> Result<Vec<u8>, String> {
+    let bytes: Result<Vec<u8>, _> = s
+        .split([':', '-'])
+        .map(|b| match b.len() {
+            1 | 2 => u8::from_str_radix(b, 16).map_err(|_| ()),
+            _ => Err(()),
+        })
+        .collect();
+    match bytes {
+        Ok(bytes) if bytes.len() == 6 => Ok(bytes),
+        _ => Err(format!("invalid MAC address '{s}'")),
+    }
+}
+
+impl FromStr for InterfaceSpec {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Some(mac) = s.strip_prefix("mac:") {
+            return parse_mac(mac).map(Self::Mac);
+        }
+        if let Some(re) = s.strip_prefix("re:") {
+            return Regex::new(re)
+                .map(Self::Regex)
+                .map_err(|e| format!("invalid regex '{re}': {e}"));
+        }
+        if let Some(index) = s.strip_prefix("index:") {
+            return index
+                .parse()
+                .map(Self::Index)
+                .map_err(|_| format!("invalid interface index '{index}'"));
+        }
+
+        Ok(match s.contains(['*', '?']) {
+            true => Self::Glob(s.to_string()),
+            false => Self::Name(s.to_string()),
+        })
+    }
+}
+
+impl fmt::Display for InterfaceSpec {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Name(name) | Self::Glob(name) => write!(f, "{name}"),
+            Self::Regex(re) => write!(f, "re:{}", re.as_str()),
+            Self::Mac(mac) => write!(f, "mac:{}", format_mac(mac)),
+            Self::Index(index) => write!(f, "index:{index}"),
+        }
+    }
+}
+
+/// Matches `*` (any run of characters) and `?` (any one character).
+fn glob_matches(pattern: &str, name: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let name: Vec<char> = name.chars().collect();
+    let (mut p, mut n) = (0, 0);
+    // Where to resume from if what followed the last `*` doesn't match
+    let mut star: Option<(usize, usize)> = None;
+
+    while n < name.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                star = Some((p, n));
+                p += 1;
+            }
+            Some(&c) if c == '?' || c == name[n] => {
+                p += 1;
+                n += 1;
+            }
+            _ => match star {
+                Some((sp, sn)) => {
+                    star = Some((sp, sn + 1));
+                    p = sp + 1;
+                    n = sn + 1;
+                }
+                None => return false,
+            },
+        }
+    }
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn globs() {
+        assert!(glob_matches("utun*", "utun3"));
+        assert!(glob_matches("utun*", "utun"));
+        assert!(glob_matches("en?", "en0"));
+        assert!(!glob_matches("en?", "en10"));
+        assert!(glob_matches("*0", "bridge0"));
+        assert!(glob_matches("e*n*0", "ethernet0"));
+        assert!(glob_matches("*", ""));
+        assert!(!glob_matches("utun*", "en0"));
+        assert!(!glob_matches("en0", "en01"));
+        // Needs to back up after the first `*` matches too little
+        assert!(glob_matches("*a*b", "xaxxab"));
+        assert!(!glob_matches("*a*b", "xaxxa"));
+    }
+
+    #[test]
+    fn parse_specs() {
+        assert!(matches!("en0".parse(), Ok(InterfaceSpec::Name(n)) if n == "en0"));
+        assert!(matches!("utun*".parse(), Ok(InterfaceSpec::Glob(p)) if p == "utun*"));
+        assert!(matches!(
+            "re:^en[0-9]+$".parse(),
+            Ok(InterfaceSpec::Regex(_))
+        ));
+        assert!(matches!("index:7".parse(), Ok(InterfaceSpec::Index(7))));
+        assert!(matches!(
+            "mac:AA-bb-cc-dd-ee-f".parse(),
+            Ok(InterfaceSpec::Mac(m)) if m == [0xaa, 0xbb, 0xcc, 0xdd, 0xee, 0x0f]
+        ));
+
+        assert!("re:(".parse::<InterfaceSpec>().is_err());
+        assert!("index:x".parse::<InterfaceSpec>().is_err());
+        assert!("mac:aa:bb:cc".parse::<InterfaceSpec>().is_err());
+        assert!("mac:aa:bb:cc:dd:ee:fff".parse::<InterfaceSpec>().is_err());
+    }
+
+    #[test]
+    fn display_round_trips() {
+        for spec in [
+            "en0",
+            "utun*",
+            "re:^en[0-9]+$",
+            "index:7",
+            "mac:aa:bb:cc:dd:ee:ff",
+        ] {
+            assert_eq!(spec.parse::<InterfaceSpec>().unwrap().to_string(), spec);
+        }
+    }
+}
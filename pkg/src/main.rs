@@ -1,74 +1,62 @@
-use std::net::{Ipv4Addr, Ipv6Addr};
+use std::net::IpAddr;
+use std::path::Path;
+use std::time::Duration;
 
 use clap::Parser;
-use flags::Args;
-use ipnetwork::{Ipv4Network, Ipv6Network};
-use libroute::addresses::{AddressOperation, SockAddr};
-use libroute::header::Header;
-use libroute::link::MessageType as LinkMessageType;
-use libroute::route::MessageType as RouteMessageType;
-use libroute::socket::{get_ifindex, ReadError, RouteSocket};
+use flags::{Args, Command};
+use libroute::addresses::format_mac;
+use libroute::socket::{Event, LookupError, ReadError, RouteSocket};
+use libroute::sysctl::{dump_interfaces, dump_routes};
 use simple_logger::SimpleLogger;
 
-use crate::flags::WaitConditionFlag;
-
-lazy_static::lazy_static! {
-    static ref ZERO_IPV4: Ipv4Addr = Ipv4Addr::from([0, 0, 0, 0]);
-    static ref ZERO_IPV6: Ipv6Addr = Ipv6Addr::from([0, 0, 0, 0, 0, 0, 0, 0]);
-
-    static ref LOCAL_IPV6_ADDR: Ipv6Addr = Ipv6Addr::from([0, 0, 0, 0, 0, 0, 0, 1]);
-    static ref LOCAL_IPV4_RANGE: Ipv4Network = Ipv4Network::new(Ipv4Addr::from([127, 0, 0, 0]), 8).unwrap();
-
-    // Link local address range: 169.254.0.0/16
-    // https://en.wikipedia.org/wiki/Link-local_address#IPv4
-    static ref LINK_LOCAL_IPV4_RANGE: Ipv4Network = Ipv4Network::new(Ipv4Addr::from([169, 254, 0, 0]), 16).unwrap();
-    // Link local address range: fe80::/10
-    // https://en.wikipedia.org/wiki/Link-local_address#IPv6
-    static ref LINK_LOCAL_IPV6_RANGE: Ipv6Network = Ipv6Network::new(Ipv6Addr::from([65152, 0, 0, 0, 0, 0, 0, 0]), 10).unwrap();
-}
+use crate::address::AddressPolicy;
+use crate::condition::WaitCondition;
+use crate::report::Report;
+use crate::state::{new_default_gateway, NetworkState};
 
+mod address;
+mod condition;
+mod dns;
 mod flags;
+mod gateway;
+mod http;
+mod interface;
+mod parser;
+mod ping;
+mod probe;
+mod report;
+mod state;
+mod tcp;
 
-#[derive(Clone, Debug)]
-enum InterfaceSpec {
-    Index(u16),
-    Name(String),
-    // Maybe Address sometime in future?
-}
-
-#[derive(Debug)]
-enum WaitCondition {
-    AnyDefaultRoute,
-    Interface(InterfaceCondition, InterfaceSpec),
-}
+/// Goes off once the condition has held for --stable-for
+const STABLE_TIMER: usize = 0;
+/// Goes off when a probe is due to run again
+const PROBE_TIMER: usize = 1;
+/// Goes off when we should check on duplicate address detection again
+const DAD_TIMER: usize = 2;
 
-#[derive(Clone, Debug)]
-enum InterfaceCondition {
-    HasAddress,
-    HasRoute,
-}
-
-fn to_ifspec(if_name: &str) -> InterfaceSpec {
-    match get_ifindex(if_name) {
-        Ok(v) => {
-            log::info!("found index {v} for interface {if_name}");
-            InterfaceSpec::Index(v as u16)
-        }
-        Err(e) => {
-            log::warn!("Fetching index for {if_name} failed: {e}, tracking new connections");
-            assert!(!if_name.is_empty());
-            InterfaceSpec::Name(if_name.to_string())
-        }
-    }
-}
+/// How often to check on addresses going through duplicate address detection
+const DAD_RECHECK_INTERVAL: Duration = Duration::from_millis(250);
 
 #[derive(thiserror::Error, Debug)]
 enum MainError {
     #[error("error checking network state: {0}")]
     Read(#[from] ReadError),
 
+    #[error("error looking up route: {0}")]
+    Lookup(#[from] LookupError),
+
+    #[error("timed out behind a captive portal")]
+    CaptivePortal,
+
+    #[error("{0} on {1} is in use by another host")]
+    DuplicateAddress(IpAddr, String),
+
     #[error("error setting logger: {0}")]
     LogInit(#[from] log::SetLoggerError),
+
+    #[error("error serialising event: {0}")]
+    Serialise(#[from] serde_json::Error),
 }
 
 fn real_main() -> Result<(), MainError> {
@@ -85,184 +73,179 @@ fn real_main() -> Result<(), MainError> {
     // let mut rs = RouteSocket::new().unwrap();
     let mut rs = RouteSocket::new(args.timeout).unwrap();
 
-    // NOTE: mut so we can eventually change this to an Index when we find one
-    // that we want
-    let mut wait_cond = match args.wait_condition {
-        WaitConditionFlag::DefaultRouteExists => WaitCondition::AnyDefaultRoute,
-        WaitConditionFlag::InterfaceHasRoute(if_name) => {
-            let spec = to_ifspec(&if_name);
-            WaitCondition::Interface(InterfaceCondition::HasRoute, spec)
-        }
-        WaitConditionFlag::InterfaceHasAddress(if_name) => {
-            let spec = to_ifspec(&if_name);
-            WaitCondition::Interface(InterfaceCondition::HasAddress, spec)
-        }
+    if let Some(Command::RouteGet { destination }) = args.command {
+        return route_get(&mut rs, destination, args.json);
+    }
+
+    let mut wait_cond = match args.until_lost {
+        true => WaitCondition::Not(Box::new(args.wait_condition.into())),
+        false => WaitCondition::from(args.wait_condition),
     };
+    log::debug!("wait_cond: {:?}", wait_cond);
 
-    match wait_cond {
-        WaitCondition::AnyDefaultRoute => rs.request_default_ipv4().unwrap(),
-        WaitCondition::Interface(_, InterfaceSpec::Index(idx)) => {
-            rs.request_interface_info(idx).unwrap()
-        }
-        WaitCondition::Interface(_, InterfaceSpec::Name(ref if_name)) => {
-            log::info!("No interface index found for {if_name}")
-        }
+    let resolv_conf = Path::new(dns::RESOLV_CONF);
+    if wait_cond.uses_dns() {
+        // Watch before reading, so we can't miss a change in between
+        rs.watch_path(resolv_conf).map_err(ReadError::from)?;
+    }
+    if let Err(e) = rs.watch_arp_collisions() {
+        log::warn!("can't watch for IPv4 address conflicts: {e}");
     }
 
-    log::debug!("wait_cond: {:?}", wait_cond);
+    let mut state = NetworkState::new(AddressPolicy {
+        exclude: args.exclude_ranges,
+        include: args.include_ranges,
+        allow_link_local: args.allow_link_local,
+    });
+    state.set_nameservers(dns::read_nameservers(resolv_conf));
 
+    // The event which made our condition hold. If it already holds at
+    // startup, that's the message from the dump which made it hold, so
+    // --json always has something to show.
+    let mut event = None;
+    let mut held = false;
+    for h in dump_interfaces()?.into_iter().chain(dump_routes()?) {
+        state.update(&h);
+        let holds = wait_cond.holds(&state);
+        if !(held && holds) {
+            event = Some(h);
+        }
+        held = holds;
+    }
+    // Whether we're waiting out --stable-for
+    let mut settling = false;
     loop {
-        let packet = rs.recv()?;
-        log::debug!("received: {}", packet.print_self());
-        match &mut wait_cond {
-            WaitCondition::AnyDefaultRoute => {
-                // This was an event which notes that a default route is up.
-                if is_ready_default_route(&packet) {
-                    return Ok(());
+        state.refresh_pending_dad();
+        // Waiting won't fix a conflict, so don't hide it behind a timeout
+        if let Some(dup) = state
+            .duplicated_addresses()
+            .find(|a| wait_cond.watches(&state, a))
+        {
+            return Err(MainError::DuplicateAddress(
+                dup.network.ip(),
+                state.interface_name(dup.index),
+            ));
+        }
+        if wait_cond.holds(&state) {
+            match args.stable_for {
+                None => break,
+                Some(window) if !settling => {
+                    log::info!("condition met, waiting {window:?} for it to settle");
+                    rs.start_timer(STABLE_TIMER, window)
+                        .map_err(ReadError::from)?;
+                    settling = true;
                 }
+                Some(_) => (),
             }
-            WaitCondition::Interface(ref mut cond, ref mut spec) => {
-                // NOTE: We permit specifying by an interface name, but this is not
-                // present in every event we receive. However, the interface index is. If
-                // we are currently looking for an index name, we also do an additional
-                // check to see if we've gotten a link event, and if it is a link event for
-                // our interface, and use that to identify the interface instead.
-                if let InterfaceSpec::Name(name) = &spec {
-                    if let Some(idx) = index_for_name(&packet, name) {
-                        *spec = InterfaceSpec::Index(idx);
-                    }
-                }
+        } else if settling {
+            log::info!("condition no longer met, starting over");
+            rs.cancel_timer(STABLE_TIMER).map_err(ReadError::from)?;
+            settling = false;
+        }
 
-                match spec {
-                    InterfaceSpec::Index(idx) => {
-                        if is_given_interface_running(&packet, cond, idx) {
-                            return Ok(());
-                        }
-                    }
-                    // We've already `continue`d above if spec is a Name
-                    InterfaceSpec::Name(_) => unreachable!(),
-                }
+        if let Some(due_in) = wait_cond.next_probe_in() {
+            rs.start_timer(PROBE_TIMER, due_in)
+                .map_err(ReadError::from)?;
+        }
+        if state.has_pending_dad() {
+            rs.start_timer(DAD_TIMER, DAD_RECHECK_INTERVAL)
+                .map_err(ReadError::from)?;
+        }
+
+        let received = match rs.recv_event() {
+            Err(ReadError::Timeout) if wait_cond.behind_captive_portal() => {
+                return Err(MainError::CaptivePortal)
             }
+            r => r?,
         };
-    }
-}
+        let packet = match received {
+            // The timer is cancelled whenever the condition breaks, so it's
+            // held the whole time.
+            Event::Timer(STABLE_TIMER) => break,
+            // Probes run, and DAD is checked on, when the condition is
+            // checked again
+            Event::Timer(_) => continue,
+            Event::PathChanged(_) => {
+                state.set_nameservers(dns::read_nameservers(resolv_conf));
+                continue;
+            }
+            // Checked on when the condition is checked again, like DAD
+            Event::ArpCollision(collision) => {
+                log::warn!(
+                    "{} on {} is also being used by {}",
+                    collision.ip,
+                    collision.interface_name,
+                    format_mac(&collision.link_layer_addr)
+                );
+                state.add_arp_collision(&collision);
+                continue;
+            }
+            Event::Message(packet) => packet,
+        };
+        log::debug!("received: {}", packet.print_self());
+        state.update(&packet);
+        wait_cond.network_changed();
 
-fn is_ready_default_route(h: &Header) -> bool {
-    // We only care about routes being added
-    let info = match h {
-        Header::Route(ref i) => i,
-        _ => return false,
-    };
+        if let Some(gateway) = new_default_gateway(&packet).filter(|_| wait_cond.uses_neighbors()) {
+            // Looking up the gateway gets us its neighbour entry, if it
+            // already has one. The kernel may refuse while routes are still
+            // changing (e.g. with ESRCH), which a later change will retry.
+            if let Err(e) = rs.request_route(gateway) {
+                log::debug!("couldn't look up gateway {gateway}: {e}");
+            }
+        }
 
-    // We only care about routes that are not being removed.
-    if !matches!(
-        info.operation,
-        RouteMessageType::Add | RouteMessageType::Get | RouteMessageType::Change
-    ) {
-        return false;
+        event = Some(packet);
     }
 
-    if !(info.flags.is_up() && info.addrs.gateway.is_some()) {
-        return false;
+    if args.json {
+        println!("{}", serde_json::to_string(&event)?);
     }
 
-    match &info.addrs.destination {
-        Some(SockAddr::V4(addr)) => {
-            if addr.ip().octets() != ZERO_IPV4.octets() {
-                log::info!("found default IPV4 route");
-                return false;
-            }
-        }
-        Some(SockAddr::V6(addr)) => {
-            if addr.ip().octets() != ZERO_IPV6.octets() {
-                log::info!("found default IPV6 route");
-                return false;
-            }
-        }
-        _ => return false,
-    };
-
-    true
-}
-
-fn is_not_local_addr(addr: &SockAddr) -> bool {
-    match addr {
-        SockAddr::V4(a) => {
-            let ip = *a.ip();
-            let b = !(LOCAL_IPV4_RANGE.contains(ip) || LINK_LOCAL_IPV4_RANGE.contains(ip));
-            log::trace!("{ip} not local? {b}");
-            // the address is NOT localhost OR a self-assigned IP
-            !(LOCAL_IPV4_RANGE.contains(ip) || LINK_LOCAL_IPV4_RANGE.contains(ip))
+    let mut report = Report::default();
+    wait_cond.report(&state, &mut report);
+    log::info!("condition met: {report:?}");
+    if args.report {
+        match args.json {
+            true => println!("{}", serde_json::to_string(&report)?),
+            false => print!("{report}"),
         }
-        SockAddr::V6(a) => {
-            let ip = *a.ip();
-            let b = !(*LOCAL_IPV6_ADDR == ip || LINK_LOCAL_IPV6_RANGE.contains(ip));
-            log::trace!("{ip} not local? {b}");
-            !(*LOCAL_IPV6_ADDR == ip || LINK_LOCAL_IPV6_RANGE.contains(ip))
-        }
-        _ => false,
     }
-}
 
-fn index_for_name(h: &Header, if_name: &str) -> Option<u16> {
-    match h.addrs().interface_link.as_ref() {
-        Some(la) => match la.interface_name.as_str() {
-            n if n == if_name => Some(la.index),
-            _ => None,
-        },
-        _ => None,
-    }
+    Ok(())
 }
 
-fn is_given_interface_running(h: &Header, condition: &InterfaceCondition, index: &u16) -> bool {
-    let idx = h.index();
-    if *index != idx {
-        log::trace!("wrong index {index}");
-        return false;
+/// Prints the route the kernel would use for `destination`, like `route get`.
+fn route_get(rs: &mut RouteSocket, destination: IpAddr, json: bool) -> Result<(), MainError> {
+    let info = rs.lookup_route(destination)?;
+    if json {
+        println!("{}", serde_json::to_string(&info)?);
+        return Ok(());
     }
 
-    let is_alive = match h {
-        Header::Link(link) => match link.operation {
-            LinkMessageType::Info => link.flags.is_up() && link.flags.is_running(),
-            _ => return false,
-        },
-        Header::Address(addr) => match addr.operation {
-            AddressOperation::Add => addr.flags.is_up() && !addr.flags.is_dead(),
-            _ => return false,
-        },
-        Header::Route(route) => match route.operation {
-            RouteMessageType::Add | RouteMessageType::Get => match route.addrs.destination.as_ref()
-            {
-                Some(dest) => {
-                    if is_not_local_addr(dest) {
-                        route.flags.is_up()
-                    } else {
-                        return false;
-                    }
-                }
-                None => false,
-            },
-            _ => return false,
-        },
+    let addrs = &info.addrs;
+    let network = match addrs.destination_network() {
+        Ok(Some(net)) if net.prefix() == 0 => "default".to_string(),
+        Ok(Some(net)) => net.to_string(),
+        _ => "?".to_string(),
     };
-
-    log::trace!("index {index} alive? {is_alive}");
-    if !is_alive {
-        // The interface (or route) is not up and alive
-        return false;
+    println!("   route to: {destination}");
+    println!("destination: {network}");
+    if let Some(gateway) = &addrs.gateway {
+        println!("    gateway: {}", gateway::describe(gateway));
     }
-
-    let addrs = h.addrs();
-    match &condition {
-        InterfaceCondition::HasRoute => match &addrs.destination {
-            Some(dest) => is_not_local_addr(dest),
-            _ => false,
-        },
-        InterfaceCondition::HasAddress => match &addrs.interface_addr {
-            Some(addr) => is_not_local_addr(addr),
-            _ => false,
-        },
+    if let Some(link) = &addrs.interface_link {
+        match link.interface_name.as_str() {
+            "" => println!("  interface: index:{}", link.index),
+            name => println!("  interface: {name}"),
+        }
     }
+    if let Some(source) = addrs.interface_addr.as_ref().and_then(|a| a.ip()) {
+        println!("     source: {source}");
+    }
+    println!("      flags: {}", info.flags.names().join(", "));
+
+    Ok(())
 }
 
 fn main() {
@@ -272,6 +255,22 @@ fn main() {
             log::error!("log initialisation error: {e}");
             1
         }
+        Err(MainError::Lookup(LookupError::NoRoute(ip))) => {
+            log::error!("no route to {ip}");
+            4
+        }
+        Err(MainError::Lookup(LookupError::Timeout)) => {
+            log::error!("timeout");
+            2
+        }
+        Err(MainError::Lookup(e)) => {
+            log::error!("error: {e}");
+            1
+        }
+        Err(MainError::Serialise(e)) => {
+            log::error!("error serialising event: {e}");
+            1
+        }
         Err(MainError::Read(ReadError::IO(e))) => {
             log::error!("error: {e}");
             1
@@ -284,6 +283,14 @@ fn main() {
             log::error!("error parsing address: {e}");
             3
         }
+        Err(MainError::CaptivePortal) => {
+            log::error!("timeout, behind a captive portal");
+            5
+        }
+        Err(e @ MainError::DuplicateAddress(..)) => {
+            log::error!("address conflict: {e}");
+            6
+        }
     };
     std::process::exit(code);
 }
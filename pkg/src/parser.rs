@@ -0,0 +1,393 @@
+//! Parser for wait condition expressions.
+//!
+//! ```text
+//! expr  := combinator "(" expr ("," expr)* ")"
+//!        | "quorum" "(" number ("," expr)+ ")"
+//!        | condition ["=" value] ("," option operator value)*
+//! combinator := "all" | "any" | "not"
+//! operator := "=" | "<" | "<="
+//! ```
+//!
+//! `<` and `<=` give limits on what a probe measures, e.g. `loss<=33%`.
+//!
+//! Options are told apart from the next expression in a list by their name,
+//! which is never the name of a condition.
+//!
+//! Values run up to the next top-level `,`, `)` or whitespace, and may be
+//! quoted with `'` or `"` if they contain any of those.
+
+use std::fmt;
+
+use crate::flags::{WaitConditionFlag, CONDITION_NAMES};
+use crate::probe::Comparison;
+
+#[derive(Debug)]
+pub struct ParseError {
+    input: String,
+    /// Byte offset into `input`
+    pos: usize,
+    message: String,
+}
+
+impl ParseError {
+    /// Where the error is, in characters rather than bytes, so it lines up
+    /// with what the user typed.
+    fn position(&self) -> usize {
+        self.input[..self.pos].chars().count()
+    }
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let offset = self.position();
+        writeln!(f, "{} (at position {offset})", self.message)?;
+        writeln!(f, "  {}", self.input)?;
+        write!(f, "  {}^", " ".repeat(offset))
+    }
+}
+
+pub fn parse(input: &str) -> Result<WaitConditionFlag, ParseError> {
+    let mut parser = Parser { input, pos: 0 };
+    let expr = parser.expr()?;
+
+    parser.skip_whitespace();
+    if parser.pos < input.len() {
+        return Err(parser.error(parser.pos, "unexpected input after condition"));
+    }
+
+    Ok(expr)
+}
+
+fn is_expr_name(name: &str) -> bool {
+    matches!(name, "all" | "any" | "not" | "quorum") || CONDITION_NAMES.contains(&name)
+}
+
+struct Parser<'a> {
+    input: &'a str,
+    pos: usize,
+}
+
+impl<'a> Parser<'a> {
+    fn error(&self, pos: usize, message: impl Into<String>) -> ParseError {
+        ParseError {
+            input: self.input.to_string(),
+            pos,
+            message: message.into(),
+        }
+    }
+
+    fn rest(&self) -> &'a str {
+        &self.input[self.pos..]
+    }
+
+    fn peek(&self) -> Option<char> {
+        self.rest().chars().next()
+    }
+
+    fn skip_whitespace(&mut self) {
+        let rest = self.rest();
+        self.pos += rest.len() - rest.trim_start().len();
+    }
+
+    fn eat(&mut self, c: char) -> bool {
+        self.skip_whitespace();
+        match self.peek() {
+            Some(p) if p == c => {
+                self.pos += c.len_utf8();
+                true
+            }
+            _ => false,
+        }
+    }
+
+    fn ident(&mut self) -> Result<(usize, &'a str), ParseError> {
+        self.skip_whitespace();
+        let start = self.pos;
+        let len = self
+            .rest()
+            .find(|c: char| !(c.is_ascii_alphanumeric() || c == '-' || c == '_'))
+            .unwrap_or(self.rest().len());
+        if len == 0 {
+            return Err(self.error(start, "expected a wait condition"));
+        }
+
+        self.pos += len;
+        Ok((start, &self.input[start..self.pos]))
+    }
+
+    fn value(&mut self) -> Result<(usize, String), ParseError> {
+        self.skip_whitespace();
+        let start = self.pos;
+
+        if let Some(quote @ ('"' | '\'')) = self.peek() {
+            let body = &self.rest()[1..];
+            return match body.find(quote) {
+                Some(end) => {
+                    self.pos += end + 2;
+                    Ok((start, body[..end].to_string()))
+                }
+                None => Err(self.error(start, "unterminated quote")),
+            };
+        }
+
+        // Parens are allowed in values (e.g. regexes), as long as they're
+        // balanced.
+        let mut depth = 0;
+        let mut len = self.rest().len();
+        for (i, c) in self.rest().char_indices() {
+            match c {
+                '(' => depth += 1,
+                ')' if depth > 0 => depth -= 1,
+                ')' => {
+                    len = i;
+                    break;
+                }
+                c if depth == 0 && (c == ',' || c.is_whitespace()) => {
+                    len = i;
+                    break;
+                }
+                _ => (),
+            }
+        }
+        if len == 0 {
+            return Err(self.error(start, "expected a value"));
+        }
+
+        self.pos += len;
+        Ok((start, self.input[start..self.pos].to_string()))
+    }
+
+    fn expr(&mut self) -> Result<WaitConditionFlag, ParseError> {
+        let (start, name) = self.ident()?;
+
+        match name {
+            "all" | "any" | "not" => {
+                if !self.eat('(') {
+                    return Err(self.error(self.pos, format!("expected '(' after {name}")));
+                }
+                let mut args = self.args()?;
+                Ok(match name {
+                    "all" => WaitConditionFlag::All(args),
+                    "any" => WaitConditionFlag::Any(args),
+                    _ => {
+                        if args.len() != 1 {
+                            return Err(self.error(start, "not() takes exactly one condition"));
+                        }
+                        WaitConditionFlag::Not(Box::new(args.remove(0)))
+                    }
+                })
+            }
+            "quorum" => {
+                if !self.eat('(') {
+                    return Err(self.error(self.pos, "expected '(' after quorum"));
+                }
+                let (pos, needed) = self.ident()?;
+                let needed: usize = needed
+                    .parse()
+                    .map_err(|_| self.error(pos, "expected how many conditions must hold"))?;
+                if !self.eat(',') {
+                    return Err(self.error(self.pos, "expected ','"));
+                }
+
+                let args = self.args()?;
+                if needed == 0 || needed > args.len() {
+                    return Err(self.error(
+                        pos,
+                        format!("quorum needs between 1 and {} conditions", args.len()),
+                    ));
+                }
+                Ok(WaitConditionFlag::Quorum(needed, args))
+            }
+            _ if !CONDITION_NAMES.contains(&name) => {
+                Err(self.error(start, format!("unknown wait condition '{name}'")))
+            }
+            _ => {
+                // Point at the value if there's a problem with it, or where
+                // it should have been if it's missing.
+                let (pos, value) = match self.eat('=') {
+                    true => {
+                        let (pos, value) = self.value()?;
+                        (pos, Some(value))
+                    }
+                    false => (self.pos, None),
+                };
+                let mut flag = WaitConditionFlag::from_parts(name, value.as_deref())
+                    .map_err(|e| self.error(pos, e))?;
+
+                while let Some((pos, key, comparison, value)) = self.option()? {
+                    match comparison {
+                        None => flag.qualify(key, &value),
+                        Some(comparison) => flag.limit(key, comparison, &value),
+                    }
+                    .map_err(|e| self.error(pos, e))?;
+                }
+                Ok(flag)
+            }
+        }
+    }
+
+    /// Parses a `,key=value` option or `,key<value` limit following a
+    /// condition, if there is one. Otherwise, leaves the comma for the
+    /// enclosing list.
+    #[allow(clippy::type_complexity)]
+    fn option(
+        &mut self,
+    ) -> Result<Option<(usize, &'a str, Option<Comparison>, String)>, ParseError> {
+        let saved = self.pos;
+        if !self.eat(',') {
+            return Ok(None);
+        }
+
+        let (start, key) = match self.ident() {
+            Ok((start, key)) if !is_expr_name(key) => (start, key),
+            _ => {
+                self.pos = saved;
+                return Ok(None);
+            }
+        };
+        let comparison = if self.eat('=') {
+            None
+        } else if self.eat('<') {
+            match self.eat('=') {
+                true => Some(Comparison::AtMost),
+                false => Some(Comparison::Below),
+            }
+        } else {
+            self.pos = saved;
+            return Ok(None);
+        };
+
+        let (_, value) = self.value()?;
+        Ok(Some((start, key, comparison, value)))
+    }
+
+    /// Parses a comma-separated list of expressions, up to and including the
+    /// closing paren.
+    fn args(&mut self) -> Result<Vec<WaitConditionFlag>, ParseError> {
+        let mut args = Vec::new();
+        loop {
+            args.push(self.expr()?);
+            if self.eat(',') {
+                continue;
+            }
+            if self.eat(')') {
+                return Ok(args);
+            }
+            return Err(self.error(self.pos, "expected ',' or ')'"));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parses_to(input: &str, want: &str) {
+        match parse(input) {
+            Ok(flag) => assert_eq!(flag.to_string(), want, "parsing {input:?}"),
+            Err(e) => panic!("failed to parse {input:?}: {e}"),
+        }
+    }
+
+    /// Gives the message and character position of the error from parsing
+    /// `input`.
+    fn fails_at(input: &str) -> (String, usize) {
+        match parse(input) {
+            Ok(flag) => panic!("{input:?} parsed as {flag}"),
+            Err(e) => (e.message.clone(), e.position()),
+        }
+    }
+
+    #[test]
+    fn single_conditions() {
+        parses_to("default-route", "default-route");
+        parses_to("if-gets-address=en0", "if-gets-address=en0");
+        parses_to(
+            "if-gets-address=en0,family=inet6,scope=global",
+            "if-gets-address=en0,family=inet6,scope=global",
+        );
+        parses_to("  route-to = 10.50.3.4 ", "route-to=10.50.3.4");
+    }
+
+    #[test]
+    fn combinators() {
+        parses_to(
+            "all(default-route, any(if-gets-address=en0, if-gets-address=en1))",
+            "all(default-route, any(if-gets-address=en0, if-gets-address=en1))",
+        );
+        parses_to("not(if-link-up=en0)", "not(if-link-up=en0)");
+        parses_to(
+            "quorum(2, tcp=a:443, tcp=b:443, ping=10.0.0.1)",
+            "quorum(2, tcp=a:443, tcp=b:443, ping=10.0.0.1)",
+        );
+    }
+
+    #[test]
+    fn options_are_told_apart_from_conditions() {
+        // `family` is an option, `if-gets-route` the next condition
+        parses_to(
+            "all(if-gets-address=en0,family=inet,if-gets-route=en0)",
+            "all(if-gets-address=en0,family=inet, if-gets-route=en0)",
+        );
+    }
+
+    #[test]
+    fn limits() {
+        parses_to(
+            "ping=10.0.0.1,loss<=33%,rtt<50ms",
+            "ping=10.0.0.1,rtt<50ms,loss<=33%",
+        );
+        let (message, _) = fails_at("default-route,loss<5%");
+        assert_eq!(message, "default-route doesn't take any limits");
+    }
+
+    #[test]
+    fn values_may_be_quoted_or_hold_parens() {
+        parses_to(
+            "if-gets-address='re:^(en|eth)[0-9]+$'",
+            "if-gets-address=re:^(en|eth)[0-9]+$",
+        );
+        parses_to(
+            "any(if-gets-address=re:^(en|eth)0$, default-route)",
+            "any(if-gets-address=re:^(en|eth)0$, default-route)",
+        );
+    }
+
+    #[test]
+    fn errors_point_at_the_problem() {
+        assert_eq!(
+            fails_at("all(default-route if-gets-address=en0)"),
+            ("expected ',' or ')'".to_string(), 18)
+        );
+        assert_eq!(
+            fails_at("all(default-route, bogus)"),
+            ("unknown wait condition 'bogus'".to_string(), 19)
+        );
+        assert_eq!(
+            fails_at("not(default-route, default-route)"),
+            ("not() takes exactly one condition".to_string(), 0)
+        );
+        assert_eq!(
+            fails_at("default-route)"),
+            ("unexpected input after condition".to_string(), 13)
+        );
+        assert_eq!(
+            fails_at("quorum(3, tcp=a:443, tcp=b:443)"),
+            ("quorum needs between 1 and 2 conditions".to_string(), 7)
+        );
+        assert_eq!(
+            fails_at("if-gets-address='en0"),
+            ("unterminated quote".to_string(), 16)
+        );
+    }
+
+    #[test]
+    fn error_position_counts_characters() {
+        // 'é' takes two bytes, but the caret should only move by one
+        let err = parse("all(if-gets-address=é, bogus)").unwrap_err();
+        assert_eq!(err.position(), 23);
+        let caret = err.to_string().lines().last().unwrap().to_string();
+        assert_eq!(caret, format!("  {}^", " ".repeat(23)));
+        assert!(err.to_string().contains("(at position 23)"));
+    }
+}
@@ -0,0 +1,351 @@
+//! Checking that a host answers ICMP echo requests, using unprivileged
+//! (`SOCK_DGRAM`) ICMP sockets so we don't need to be root.
+//!
+//! On Linux, these are only allowed for groups within the
+//! `net.ipv4.ping_group_range` sysctl (which covers IPv6 too).
+
+use std::net::{IpAddr, SocketAddr, UdpSocket};
+use std::time::{Duration, Instant};
+use std::{fmt, io};
+
+use nix::errno::Errno;
+use nix::sys::socket::{socket, AddressFamily, SockFlag, SockProtocol, SockType};
+
+use crate::flags::parse_duration;
+use crate::probe::{Comparison, Probe, Samples, Stats, Thresholds};
+use crate::state::NetworkState;
+
+/// How many echo requests to send each time, unless given with `count=`
+const DEFAULT_COUNT: u16 = 3;
+
+/// How long to wait for replies, unless given with `timeout=`
+const DEFAULT_REPLY_TIMEOUT: Duration = Duration::from_secs(1);
+
+const ICMP_ECHO_REQUEST: u8 = 8;
+const ICMP_ECHO_REPLY: u8 = 0;
+const ICMPV6_ECHO_REQUEST: u8 = 128;
+const ICMPV6_ECHO_REPLY: u8 = 129;
+
+/// Why Linux refuses unprivileged ICMP sockets
+const PING_GROUP_HINT: &str =
+    "ICMP sockets aren't allowed for this group (see the net.ipv4.ping_group_range sysctl)";
+
+/// `ping=IP` and its options.
+#[derive(Clone, Debug)]
+pub struct PingOptions {
+    pub target: IpAddr,
+    /// Echo requests to send each round
+    pub count: u16,
+    /// How long to wait for replies, once a round has been sent
+    pub timeout: Duration,
+    /// Limits on round trip time and loss. These are judged over the last
+    /// round unless given a window.
+    pub thresholds: Thresholds,
+}
+
+impl PingOptions {
+    pub fn new(target: IpAddr) -> Self {
+        Self {
+            target,
+            count: DEFAULT_COUNT,
+            timeout: DEFAULT_REPLY_TIMEOUT,
+            thresholds: Thresholds::default(),
+        }
+    }
+
+    /// Applies a `key=value` option.
+    pub fn qualify(&mut self, key: &str, value: &str) -> Result<(), String> {
+        match key {
+            "count" => {
+                self.count = match value.parse() {
+                    Ok(count @ 1..) => count,
+                    _ => return Err(format!("invalid count '{value}'")),
+                }
+            }
+            "timeout" => self.timeout = parse_duration(value)?,
+            "window" => self.thresholds.set_window(value)?,
+            "loss" | "rtt" => return Err(format!("{key} takes a limit, e.g. {key}<={value}")),
+            _ => {
+                return Err(format!(
+                    "unknown option '{key}' (expected count, timeout or window)"
+                ))
+            }
+        }
+        Ok(())
+    }
+
+    /// Applies a `key<value` or `key<=value` limit.
+    pub fn limit(&mut self, key: &str, comparison: Comparison, value: &str) -> Result<(), String> {
+        self.thresholds.limit(key, comparison, value)
+    }
+}
+
+impl fmt::Display for PingOptions {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.target)?;
+        if self.count != DEFAULT_COUNT {
+            write!(f, ",count={}", self.count)?;
+        }
+        if self.timeout != DEFAULT_REPLY_TIMEOUT {
+            write!(f, ",timeout={:?}", self.timeout)?;
+        }
+        write!(f, "{}", self.thresholds)
+    }
+}
+
+/// The `ping` condition, along with its probe's progress.
+#[derive(Debug)]
+pub struct PingCheck {
+    pub options: PingOptions,
+    /// The round trip time of each echo request in a round, or `None` for
+    /// each one which wasn't answered
+    probe: Probe<Vec<Option<Duration>>>,
+    samples: Samples,
+    ok: bool,
+    /// Carries on across rounds, so late replies from one can't be taken for
+    /// replies in the next
+    seq: u16,
+}
+
+impl PingCheck {
+    pub fn new(options: PingOptions) -> Self {
+        let window = options.thresholds.window.unwrap_or(options.count.into());
+        Self {
+            probe: Probe::new(options.timeout),
+            options,
+            samples: Samples::new(window),
+            ok: false,
+            seq: 0,
+        }
+    }
+
+    pub fn holds(&mut self, state: &NetworkState) -> bool {
+        let target = self.options.target;
+        if !state.is_routable(target) {
+            log::trace!("no route to {target} to ping");
+            return false;
+        }
+
+        if let Some(result) = self.probe.finished() {
+            // By default, any reply will do
+            self.ok = match result {
+                Ok(rtts) => {
+                    for rtt in &rtts {
+                        self.samples.record(*rtt);
+                    }
+                    let replies = rtts.iter().flatten().count();
+                    log::debug!("{replies} of {} pings to {target} answered", rtts.len());
+                    replies > 0 && self.options.thresholds.allow(&self.samples)
+                }
+                Err(e) => {
+                    log::debug!("couldn't ping {target}: {e}");
+                    self.samples.record(None);
+                    false
+                }
+            };
+            self.probe.record(self.ok);
+        }
+        if self.probe.is_due() {
+            let (first, count) = (self.seq, self.options.count);
+            self.seq = self.seq.wrapping_add(count);
+            self.probe
+                .start(move |deadline| ping(target, first, count, deadline));
+        }
+        self.ok
+    }
+
+    /// Round trip times and loss over the window.
+    pub fn stats(&self) -> Stats {
+        self.samples.stats()
+    }
+
+    /// How long until the probe should be checked on.
+    pub fn next_probe_in(&self) -> Option<Duration> {
+        self.probe.next_check_in()
+    }
+
+    pub fn network_changed(&mut self) {
+        self.probe.network_changed();
+    }
+}
+
+/// Sends a round of `count` echo requests all at once, numbered on from
+/// `seq`, then collects replies until they're all in or `deadline` passes.
+/// Gives the round trip time of each request, or `None` for each one which
+/// wasn't answered.
+fn ping(
+    target: IpAddr,
+    seq: u16,
+    count: u16,
+    deadline: Instant,
+) -> io::Result<Vec<Option<Duration>>> {
+    let socket = open_socket(target)?;
+    // The kernel may replace the identifier with one of its own, so we
+    // recognise our replies by their payload instead.
+    let token = std::process::id().to_be_bytes();
+
+    let mut sent = Vec::with_capacity(count.into());
+    for i in 0..count {
+        socket.send(&echo_request(target, seq.wrapping_add(i), &token))?;
+        sent.push(Instant::now());
+    }
+
+    let mut rtts = vec![None; count.into()];
+    while rtts.contains(&None) {
+        let reply = match wait_for_reply(&socket, target, &token, deadline)? {
+            Some(reply) => reply,
+            None => break,
+        };
+        // Anything else is left over from an earlier round
+        let i = usize::from(reply.wrapping_sub(seq));
+        if let Some(rtt @ None) = rtts.get_mut(i) {
+            let took = sent[i].elapsed();
+            log::trace!("reply {reply} from {target} in {took:?}");
+            *rtt = Some(took);
+        }
+    }
+
+    Ok(rtts)
+}
+
+fn open_socket(target: IpAddr) -> io::Result<UdpSocket> {
+    let (family, protocol) = match target {
+        IpAddr::V4(_) => (AddressFamily::Inet, SockProtocol::Icmp),
+        IpAddr::V6(_) => (AddressFamily::Inet6, SockProtocol::IcmpV6),
+    };
+    let fd =
+        socket(family, SockType::Datagram, SockFlag::empty(), protocol).map_err(|e| match e {
+            Errno::EACCES if cfg!(target_os = "linux") => {
+                io::Error::new(io::ErrorKind::PermissionDenied, PING_GROUP_HINT)
+            }
+            e => e.into(),
+        })?;
+
+    // A datagram ICMP socket behaves enough like a UDP one for std to drive
+    let socket = UdpSocket::from(fd);
+    socket.connect(SocketAddr::new(target, 0))?;
+    Ok(socket)
+}
+
+fn echo_request(target: IpAddr, seq: u16, token: &[u8]) -> Vec<u8> {
+    let kind = match target {
+        IpAddr::V4(_) => ICMP_ECHO_REQUEST,
+        IpAddr::V6(_) => ICMPV6_ECHO_REQUEST,
+    };
+    let mut packet = vec![kind, 0, 0, 0, 0, 0];
+    packet.extend_from_slice(&seq.to_be_bytes());
+    packet.extend_from_slice(token);
+
+    // The kernel fills in the ICMPv6 checksum, as it covers the IPv6 header
+    if target.is_ipv4() {
+        let sum = checksum(&packet);
+        packet[2..4].copy_from_slice(&sum.to_be_bytes());
+    }
+    packet
+}
+
+/// The internet checksum from RFC 1071.
+fn checksum(data: &[u8]) -> u16 {
+    let mut sum: u32 = data
+        .chunks(2)
+        .map(|c| u32::from(u16::from_be_bytes([c[0], *c.get(1).unwrap_or(&0)])))
+        .sum();
+    while sum > 0xffff {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    !(sum as u16)
+}
+
+/// Waits for a reply to one of our echo requests, and gives its sequence
+/// number, or `None` if `deadline` passes first.
+fn wait_for_reply(
+    socket: &UdpSocket,
+    target: IpAddr,
+    token: &[u8],
+    deadline: Instant,
+) -> io::Result<Option<u16>> {
+    // A socket of one family can still be handed ICMP of the other, e.g. on
+    // loopback, so only take replies of the type we're after
+    let expected = match target {
+        IpAddr::V4(_) => ICMP_ECHO_REPLY,
+        IpAddr::V6(_) => ICMPV6_ECHO_REPLY,
+    };
+    let mut buf = [0u8; 1500];
+    loop {
+        let left = deadline.saturating_duration_since(Instant::now());
+        if left.is_zero() {
+            return Ok(None);
+        }
+        socket.set_read_timeout(Some(left))?;
+
+        let n = match socket.recv(&mut buf) {
+            Ok(n) => n,
+            Err(e)
+                if matches!(
+                    e.kind(),
+                    io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+                ) =>
+            {
+                return Ok(None)
+            }
+            Err(e) => return Err(e),
+        };
+
+        // macOS includes the IPv4 header, Linux doesn't
+        let mut packet = &buf[..n];
+        if target.is_ipv4() && packet.first().is_some_and(|b| b >> 4 == 4) {
+            let header_len = usize::from(packet[0] & 0x0f) * 4;
+            packet = packet.get(header_len..).unwrap_or_default();
+        }
+
+        let ours = packet.first() == Some(&expected)
+            && packet.len() >= 8 + token.len()
+            && &packet[8..8 + token.len()] == token;
+        if ours {
+            return Ok(Some(u16::from_be_bytes([packet[6], packet[7]])));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{Ipv4Addr, Ipv6Addr};
+
+    use super::*;
+
+    fn ping_round(target: IpAddr, seq: u16) -> Vec<Option<Duration>> {
+        let deadline = Instant::now() + Duration::from_secs(1);
+        ping(target, seq, 3, deadline).unwrap_or_else(|e| panic!("couldn't ping {target}: {e}"))
+    }
+
+    // These need ICMP sockets, which Linux only allows for the groups in the
+    // net.ipv4.ping_group_range sysctl, so run them with --ignored where
+    // that's set up.
+
+    #[test]
+    #[ignore = "needs ICMP sockets"]
+    fn loopback_v4() {
+        let rtts = ping_round(Ipv4Addr::LOCALHOST.into(), 0);
+        assert!(rtts.iter().all(Option::is_some), "{rtts:?}");
+    }
+
+    #[test]
+    #[ignore = "needs ICMP sockets"]
+    fn loopback_v6() {
+        let rtts = ping_round(Ipv6Addr::LOCALHOST.into(), 0);
+        assert!(rtts.iter().all(Option::is_some), "{rtts:?}");
+    }
+
+    #[test]
+    #[ignore = "needs ICMP sockets"]
+    fn sequence_numbers_wrap() {
+        let rtts = ping_round(Ipv4Addr::LOCALHOST.into(), u16::MAX);
+        assert!(rtts.iter().all(Option::is_some), "{rtts:?}");
+    }
+
+    #[test]
+    fn checksums() {
+        let request = echo_request(Ipv4Addr::LOCALHOST.into(), 1, &[0, 0, 0, 1]);
+        assert_eq!(checksum(&request), 0);
+    }
+}
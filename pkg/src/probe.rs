@@ -0,0 +1,487 @@
+//! Shared bits for conditions which actively probe the network, rather than
+//! only watching routing messages.
+
+use std::collections::VecDeque;
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+use std::{fmt, io};
+
+use crate::flags::parse_duration;
+
+/// How long to wait after the first failed attempt before trying again. Also
+/// the shortest time between wakeups for probes, so that ones which are
+/// overdue but not being checked (e.g. in an `all` which already failed)
+/// can't spin.
+const MIN_BACKOFF: Duration = Duration::from_millis(250);
+/// How often to check on an attempt running in the background
+const POLL_INTERVAL: Duration = Duration::from_millis(50);
+/// How long past its deadline an attempt has to report back, as ones which
+/// wait until the deadline (e.g. for ping replies) finish just after it
+const OVERRUN: Duration = Duration::from_millis(100);
+/// How many attempts `rtt` and `loss` limits are judged over, unless given
+/// with `window=`
+pub const DEFAULT_WINDOW: usize = 5;
+/// The longest to wait between failed attempts
+const MAX_BACKOFF: Duration = Duration::from_secs(5);
+/// How often to check again after a successful attempt, e.g. while waiting
+/// out --stable-for
+const RECHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// When a probe should next run. Failed attempts back off exponentially.
+#[derive(Debug)]
+pub struct Schedule {
+    /// `None` until the first attempt, which is due straight away
+    next: Option<Instant>,
+    backoff: Duration,
+    /// When the last attempt was made, and whether it succeeded
+    last: Option<(Instant, bool)>,
+}
+
+impl Default for Schedule {
+    fn default() -> Self {
+        Self {
+            next: None,
+            backoff: MIN_BACKOFF,
+            last: None,
+        }
+    }
+}
+
+impl Schedule {
+    pub fn is_due(&self) -> bool {
+        self.next.map(|n| Instant::now() >= n).unwrap_or(true)
+    }
+
+    /// How long until the next attempt is due, once there's been a first
+    /// one. Until then, the probe runs whenever its condition is checked.
+    pub fn due_in(&self) -> Option<Duration> {
+        self.next
+            .map(|n| n.saturating_duration_since(Instant::now()).max(MIN_BACKOFF))
+    }
+
+    pub fn record(&mut self, ok: bool) {
+        let now = Instant::now();
+        self.last = Some((now, ok));
+        if ok {
+            self.backoff = MIN_BACKOFF;
+            self.next = Some(now + RECHECK_INTERVAL);
+        } else {
+            self.next = Some(now + self.backoff);
+            self.backoff = (self.backoff * 2).min(MAX_BACKOFF);
+        }
+    }
+
+    /// Brings a failing probe forward after a change to the network, which
+    /// may well have fixed it. It still won't run more often than every
+    /// MIN_BACKOFF, as changes tend to come in bursts.
+    pub fn network_changed(&mut self) {
+        if let Some((at, false)) = self.last {
+            self.backoff = MIN_BACKOFF;
+            self.next = Some(at + MIN_BACKOFF);
+        }
+    }
+}
+
+/// Runs a probe's attempts on its schedule, each on a thread of its own, so
+/// that waiting on the network never holds up the routing socket.
+///
+/// Each attempt gets one deadline for everything it does, e.g. resolving a
+/// name as well as connecting. One which hasn't finished by then has failed,
+/// even if it's stuck somewhere without a timeout of its own.
+#[derive(Debug)]
+pub struct Probe<T> {
+    schedule: Schedule,
+    timeout: Duration,
+    /// The attempt in progress, and its deadline
+    running: Option<(Instant, JoinHandle<io::Result<T>>)>,
+}
+
+impl<T: Send + 'static> Probe<T> {
+    pub fn new(timeout: Duration) -> Self {
+        Self {
+            schedule: Schedule::default(),
+            timeout,
+            running: None,
+        }
+    }
+
+    /// Whether a new attempt should be started.
+    pub fn is_due(&self) -> bool {
+        self.running.is_none() && self.schedule.is_due()
+    }
+
+    /// Starts an attempt in the background, which is given its deadline.
+    pub fn start<F>(&mut self, attempt: F)
+    where
+        F: FnOnce(Instant) -> io::Result<T> + Send + 'static,
+    {
+        let deadline = Instant::now() + self.timeout;
+        self.running = Some((deadline, thread::spawn(move || attempt(deadline))));
+    }
+
+    /// Gives the outcome of the attempt in progress once it's over. It
+    /// should then be recorded, so the probe knows when to run next.
+    pub fn finished(&mut self) -> Option<io::Result<T>> {
+        let (deadline, handle) = self.running.take()?;
+        if handle.is_finished() {
+            return Some(
+                handle
+                    .join()
+                    .unwrap_or_else(|_| Err(io::Error::other("probe attempt panicked"))),
+            );
+        }
+        if Instant::now() >= deadline + OVERRUN {
+            // The thread is left to finish on its own
+            return Some(Err(io::ErrorKind::TimedOut.into()));
+        }
+        self.running = Some((deadline, handle));
+        None
+    }
+
+    pub fn record(&mut self, ok: bool) {
+        self.schedule.record(ok);
+    }
+
+    /// How long until the probe needs checking on, either to pick up the
+    /// attempt in progress or to start the next one.
+    pub fn next_check_in(&self) -> Option<Duration> {
+        match &self.running {
+            Some((deadline, handle))
+                if !handle.is_finished() && Instant::now() < *deadline + OVERRUN =>
+            {
+                Some(POLL_INTERVAL)
+            }
+            // Over, but not picked up, e.g. because the condition isn't being
+            // checked while another one fails
+            Some(_) => Some(MIN_BACKOFF),
+            None => self.schedule.due_in(),
+        }
+    }
+
+    pub fn network_changed(&mut self) {
+        self.schedule.network_changed();
+    }
+}
+
+/// How a measurement is compared against a limit, e.g. the `<=` in
+/// `loss<=33%`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Comparison {
+    Below,
+    AtMost,
+}
+
+impl fmt::Display for Comparison {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Below => write!(f, "<"),
+            Self::AtMost => write!(f, "<="),
+        }
+    }
+}
+
+/// An upper bound on a measurement, such as packet loss.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Limit {
+    pub comparison: Comparison,
+    pub value: f64,
+}
+
+impl Limit {
+    pub fn allows(&self, measured: f64) -> bool {
+        match self.comparison {
+            Comparison::Below => measured < self.value,
+            Comparison::AtMost => measured <= self.value,
+        }
+    }
+}
+
+/// Parses a percentage such as `33%` (the `%` is optional).
+pub fn parse_percentage(s: &str) -> Result<f64, String> {
+    match s.strip_suffix('%').unwrap_or(s).parse::<f64>() {
+        Ok(p) if (0.0..=100.0).contains(&p) => Ok(p),
+        _ => Err(format!("invalid percentage '{s}'")),
+    }
+}
+
+/// `rtt<...` and `loss<...` limits on a probe, judged over a sliding window
+/// of its most recent attempts.
+#[derive(Clone, Debug, Default)]
+pub struct Thresholds {
+    /// Median round trip time, in milliseconds
+    pub rtt: Option<Limit>,
+    /// Percentage of attempts which failed
+    pub loss: Option<Limit>,
+    /// How many attempts to judge, if not the probe's default
+    pub window: Option<usize>,
+}
+
+impl Thresholds {
+    /// Applies a `key<value` or `key<=value` limit.
+    pub fn limit(&mut self, key: &str, comparison: Comparison, value: &str) -> Result<(), String> {
+        match key {
+            "rtt" => {
+                let rtt = parse_duration(value)?;
+                self.rtt = Some(Limit {
+                    comparison,
+                    value: rtt.as_secs_f64() * 1000.0,
+                });
+            }
+            "loss" => {
+                self.loss = Some(Limit {
+                    comparison,
+                    value: parse_percentage(value)?,
+                });
+            }
+            _ => return Err(format!("unknown limit '{key}' (expected rtt or loss)")),
+        }
+        Ok(())
+    }
+
+    /// Applies `window=N`.
+    pub fn set_window(&mut self, value: &str) -> Result<(), String> {
+        match value.parse() {
+            Ok(window @ 1..) => {
+                self.window = Some(window);
+                Ok(())
+            }
+            _ => Err(format!("invalid window '{value}'")),
+        }
+    }
+
+    /// Whether the attempts in `samples` are good enough. With no limits,
+    /// there's nothing to judge. Otherwise, the window has to have filled
+    /// up first.
+    pub fn allow(&self, samples: &Samples) -> bool {
+        if self.rtt.is_none() && self.loss.is_none() {
+            return true;
+        }
+        if !samples.is_full() {
+            log::trace!("only {} attempts so far", samples.len());
+            return false;
+        }
+
+        let stats = samples.stats();
+        let rtt_ok = match (self.rtt, stats.p50) {
+            (None, _) => true,
+            (Some(limit), Some(p50)) => limit.allows(p50.as_secs_f64() * 1000.0),
+            (Some(_), None) => false,
+        };
+        let loss_ok = self.loss.map(|l| l.allows(stats.loss)).unwrap_or(true);
+        log::trace!("{stats}: rtt ok? {rtt_ok}, loss ok? {loss_ok}");
+        rtt_ok && loss_ok
+    }
+}
+
+impl fmt::Display for Thresholds {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let Some(rtt) = self.rtt {
+            write!(f, ",rtt{}{}ms", rtt.comparison, rtt.value)?;
+        }
+        if let Some(loss) = self.loss {
+            write!(f, ",loss{}{}%", loss.comparison, loss.value)?;
+        }
+        if let Some(window) = self.window {
+            write!(f, ",window={window}")?;
+        }
+        Ok(())
+    }
+}
+
+/// The outcomes of a probe's most recent attempts: the round trip time of
+/// each one which succeeded, or `None` for each one which didn't.
+#[derive(Debug)]
+pub struct Samples {
+    window: usize,
+    samples: VecDeque<Option<Duration>>,
+}
+
+impl Samples {
+    pub fn new(window: usize) -> Self {
+        Self {
+            window,
+            samples: VecDeque::with_capacity(window),
+        }
+    }
+
+    pub fn record(&mut self, rtt: Option<Duration>) {
+        if self.samples.len() == self.window {
+            self.samples.pop_front();
+        }
+        self.samples.push_back(rtt);
+    }
+
+    pub fn len(&self) -> usize {
+        self.samples.len()
+    }
+
+    pub fn is_full(&self) -> bool {
+        self.samples.len() == self.window
+    }
+
+    pub fn stats(&self) -> Stats {
+        let mut rtts: Vec<Duration> = self.samples.iter().flatten().copied().collect();
+        rtts.sort();
+        let lost = self.samples.len() - rtts.len();
+
+        Stats {
+            p50: percentile(&rtts, 50),
+            p95: percentile(&rtts, 95),
+            loss: match self.samples.len() {
+                0 => 0.0,
+                n => lost as f64 * 100.0 / n as f64,
+            },
+        }
+    }
+}
+
+/// Nearest-rank percentile of some sorted durations.
+fn percentile(sorted: &[Duration], p: usize) -> Option<Duration> {
+    let rank = (p * sorted.len()).div_ceil(100);
+    sorted.get(rank.saturating_sub(1)).copied()
+}
+
+/// What a probe measured over its window.
+#[derive(Clone, Copy, Debug)]
+pub struct Stats {
+    /// Round trip times, if any attempts succeeded
+    pub p50: Option<Duration>,
+    pub p95: Option<Duration>,
+    /// Percentage of attempts which failed
+    pub loss: f64,
+}
+
+impl fmt::Display for Stats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let (Some(p50), Some(p95)) = (self.p50, self.p95) {
+            write!(f, "rtt p50 {p50:.1?}, p95 {p95:.1?}, ")?;
+        }
+        write!(f, "loss {:.0}%", self.loss)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ms(n: u64) -> Duration {
+        Duration::from_millis(n)
+    }
+
+    #[test]
+    fn percentiles() {
+        let sorted: Vec<Duration> = (1..=10).map(ms).collect();
+        assert_eq!(percentile(&sorted, 50), Some(ms(5)));
+        assert_eq!(percentile(&sorted, 95), Some(ms(10)));
+        assert_eq!(percentile(&sorted, 0), Some(ms(1)));
+        assert_eq!(percentile(&[ms(7)], 50), Some(ms(7)));
+        assert_eq!(percentile(&[ms(1), ms(2)], 50), Some(ms(1)));
+        assert_eq!(percentile(&[], 50), None);
+    }
+
+    #[test]
+    fn samples_slide() {
+        let mut samples = Samples::new(3);
+        samples.record(Some(ms(30)));
+        samples.record(None);
+        assert!(!samples.is_full());
+        samples.record(Some(ms(10)));
+        assert!(samples.is_full());
+
+        let stats = samples.stats();
+        assert_eq!(stats.p50, Some(ms(10)));
+        assert_eq!(stats.p95, Some(ms(30)));
+        assert!((stats.loss - 100.0 / 3.0).abs() < 0.001);
+
+        // The loss drops out of the window
+        samples.record(Some(ms(20)));
+        samples.record(Some(ms(20)));
+        assert_eq!(samples.len(), 3);
+        let stats = samples.stats();
+        assert_eq!(stats.p50, Some(ms(20)));
+        assert_eq!(stats.loss, 0.0);
+    }
+
+    #[test]
+    fn nothing_answered() {
+        let mut samples = Samples::new(2);
+        assert_eq!(samples.stats().loss, 0.0);
+        samples.record(None);
+        samples.record(None);
+        let stats = samples.stats();
+        assert_eq!((stats.p50, stats.loss), (None, 100.0));
+    }
+
+    #[test]
+    fn thresholds() {
+        let mut thresholds = Thresholds::default();
+        let mut samples = Samples::new(2);
+        // Without limits, there's nothing to wait for
+        assert!(thresholds.allow(&samples));
+
+        thresholds.limit("rtt", Comparison::Below, "50ms").unwrap();
+        thresholds.limit("loss", Comparison::AtMost, "50%").unwrap();
+        samples.record(Some(ms(10)));
+        assert!(!thresholds.allow(&samples), "the window isn't full");
+        samples.record(None);
+        assert!(thresholds.allow(&samples));
+        samples.record(None);
+        assert!(!thresholds.allow(&samples), "everything was lost");
+
+        samples.record(Some(ms(60)));
+        samples.record(Some(ms(60)));
+        assert!(!thresholds.allow(&samples), "too slow");
+    }
+
+    /// Waits for `probe`'s attempt to be over.
+    fn outcome<T: Send + 'static>(probe: &mut Probe<T>) -> io::Result<T> {
+        loop {
+            if let Some(result) = probe.finished() {
+                return result;
+            }
+            thread::sleep(ms(10));
+        }
+    }
+
+    #[test]
+    fn attempts_run_in_the_background() {
+        let mut probe = Probe::new(ms(500));
+        assert!(probe.is_due());
+        probe.start(|_| {
+            thread::sleep(ms(50));
+            Ok(42)
+        });
+        assert!(!probe.is_due(), "one attempt at a time");
+        assert_eq!(probe.next_check_in(), Some(POLL_INTERVAL));
+        assert_eq!(outcome(&mut probe).unwrap(), 42);
+
+        // Not due again until the outcome is recorded
+        probe.record(true);
+        assert!(!probe.is_due());
+        assert!(probe.next_check_in() > Some(RECHECK_INTERVAL - ms(100)));
+    }
+
+    #[test]
+    fn attempts_have_a_deadline() {
+        let mut probe = Probe::new(ms(50));
+        let start = Instant::now();
+        probe.start(move |deadline| {
+            assert!(deadline <= start + ms(50) + ms(10));
+            // Stuck, e.g. on a name lookup
+            thread::sleep(ms(1000));
+            Ok(())
+        });
+        let err = outcome(&mut probe).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+        assert!(start.elapsed() < ms(500));
+    }
+
+    #[test]
+    fn percentages() {
+        assert_eq!(parse_percentage("33%"), Ok(33.0));
+        assert_eq!(parse_percentage("2.5"), Ok(2.5));
+        assert!(parse_percentage("101%").is_err());
+        assert!(parse_percentage("-1").is_err());
+        assert!(parse_percentage("lots").is_err());
+    }
+}
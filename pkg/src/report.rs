@@ -0,0 +1,93 @@
+//! What made the wait condition hold, printed with `--report`.
+
+use std::fmt;
+
+use libroute::table::Route;
+use serde::Serialize;
+
+use crate::gateway;
+use crate::probe::Stats;
+use crate::state::NetworkState;
+
+/// Serialised as e.g. `{"interfaces": ["utun3"], "gateways": ["10.0.0.1"],
+/// "probes": []}`.
+#[derive(Debug, Default, Serialize)]
+pub struct Report {
+    /// Interfaces which satisfied a condition, by name where we know it
+    pub interfaces: Vec<String>,
+    /// Gateways of the default routes which satisfied a condition
+    pub gateways: Vec<String>,
+    /// How each member of a quorum did, whether it passed or not
+    pub probes: Vec<ProbeResult>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ProbeResult {
+    pub condition: String,
+    pub passed: bool,
+    /// What was measured over the probe's window, if it measures anything
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub rtt_p50_ms: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub rtt_p95_ms: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub loss_percent: Option<f64>,
+}
+
+impl Report {
+    pub fn add_interface(&mut self, state: &NetworkState, index: u16) {
+        let name = state.interface_name(index);
+        if !self.interfaces.contains(&name) {
+            self.interfaces.push(name);
+        }
+    }
+
+    pub fn add_gateway(&mut self, route: &Route) {
+        if let Some(gw) = &route.gateway {
+            let gw = gateway::describe(gw);
+            if !self.gateways.contains(&gw) {
+                self.gateways.push(gw);
+            }
+        }
+    }
+
+    pub fn add_probe(&mut self, condition: &str, passed: bool, stats: Option<Stats>) {
+        if self.probes.iter().any(|p| p.condition == condition) {
+            return;
+        }
+        let ms = |d: std::time::Duration| d.as_secs_f64() * 1000.0;
+        self.probes.push(ProbeResult {
+            condition: condition.to_string(),
+            passed,
+            rtt_p50_ms: stats.and_then(|s| s.p50).map(ms),
+            rtt_p95_ms: stats.and_then(|s| s.p95).map(ms),
+            loss_percent: stats.map(|s| s.loss),
+        });
+    }
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for name in &self.interfaces {
+            writeln!(f, "interface: {name}")?;
+        }
+        for gw in &self.gateways {
+            writeln!(f, "gateway: {gw}")?;
+        }
+        for probe in &self.probes {
+            let result = match probe.passed {
+                true => "passed",
+                false => "failed",
+            };
+            write!(f, "probe: {} {result}", probe.condition)?;
+            if let (Some(p50), Some(p95)) = (probe.rtt_p50_ms, probe.rtt_p95_ms) {
+                write!(f, ", rtt p50 {p50:.1}ms, p95 {p95:.1}ms")?;
+            }
+            if let Some(loss) = probe.loss_percent {
+                write!(f, ", loss {loss:.0}%")?;
+            }
+            writeln!(f)?;
+        }
+        Ok(())
+    }
+}
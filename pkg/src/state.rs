@@ -0,0 +1,297 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+
+use ipnetwork::IpNetwork;
+use libroute::addresses::{AddressInfo, AddressOperation};
+use libroute::header::{interface_index_to_name, Header};
+use libroute::in6::{address_flags, Ipv6AddressFlags};
+use libroute::kern_event::ArpCollision;
+use libroute::link::{LinkInfo, MessageType as LinkMessageType};
+use libroute::neighbor::NeighborTable;
+use libroute::route::MessageType as RouteMessageType;
+use libroute::table::{Route, RoutingTable};
+
+use crate::address::AddressPolicy;
+
+#[derive(Debug)]
+pub struct Link {
+    pub index: u16,
+    pub name: Option<String>,
+    pub mac: Option<Vec<u8>>,
+    pub is_up: bool,
+    pub is_running: bool,
+}
+
+#[derive(Debug)]
+pub struct Address {
+    pub index: u16,
+    /// The interface's address, along with the prefix length of its subnet
+    pub network: IpNetwork,
+    /// For IPv6 addresses, whether they're tentative, duplicated, etc.
+    pub ipv6_flags: Option<Ipv6AddressFlags>,
+    /// For IPv4 addresses, whether the kernel saw another host using it
+    pub arp_collision: bool,
+}
+
+impl Address {
+    /// Whether the address has passed duplicate address detection. IPv4
+    /// addresses have nothing to wait for, but can still be found to be in
+    /// use elsewhere.
+    pub fn has_passed_dad(&self) -> bool {
+        !self.arp_collision && self.ipv6_flags.map(|f| f.has_passed_dad()).unwrap_or(true)
+    }
+
+    /// Whether another host was found to be using the address.
+    pub fn is_duplicated(&self) -> bool {
+        self.arp_collision || self.ipv6_flags.is_some_and(|f| f.is_duplicated())
+    }
+}
+
+/// What we know about the network, kept up to date from routing messages.
+///
+/// Conditions are checked against this rather than against single events,
+/// so that we know whether they *still* hold.
+#[derive(Debug, Default)]
+pub struct NetworkState {
+    links: HashMap<u16, Link>,
+    addresses: Vec<Address>,
+    routes: RoutingTable,
+    neighbors: NeighborTable,
+    /// From resolv.conf
+    nameservers: Vec<IpAddr>,
+    /// Which addresses and routes count towards being online
+    policy: AddressPolicy,
+}
+
+impl NetworkState {
+    pub fn new(policy: AddressPolicy) -> Self {
+        Self {
+            policy,
+            ..Default::default()
+        }
+    }
+
+    /// Applies a message to the state.
+    pub fn update(&mut self, h: &Header) {
+        // NOTE: Not every event tells us the name or MAC of its interface,
+        // so we take them from any event that does.
+        if let Some(la) = h.addrs().interface_link.as_ref() {
+            if !la.interface_name.is_empty() {
+                self.link_mut(la.index).name = Some(la.interface_name.clone());
+            }
+            if !la.link_layer_addr.is_empty() {
+                self.link_mut(la.index).mac = Some(la.link_layer_addr.clone());
+            }
+        }
+
+        match h {
+            Header::Link(link) => self.update_link(link),
+            Header::Address(addr) => self.update_address(addr),
+            Header::Route(route) if route.flags.is_llinfo() => {
+                self.neighbors.update(h);
+            }
+            Header::Route(_) => {
+                self.routes.update(h);
+            }
+        }
+    }
+
+    fn link_mut(&mut self, index: u16) -> &mut Link {
+        self.links.entry(index).or_insert_with(|| Link {
+            index,
+            name: interface_index_to_name(index.into()),
+            mac: None,
+            // NOTE: Until we get link info, the link doesn't count as up.
+            // Every link gets some from the dump at startup, and new links
+            // announce themselves when they come up.
+            is_up: false,
+            is_running: false,
+        })
+    }
+
+    fn update_link(&mut self, info: &LinkInfo) {
+        if !matches!(info.operation, LinkMessageType::Info) {
+            return;
+        }
+
+        let link = self.link_mut(info.index);
+        link.is_up = info.flags.is_up();
+        link.is_running = info.flags.is_running();
+        log::debug!(
+            "link {} ({:?}): up {}, running {}",
+            link.index,
+            link.name,
+            link.is_up,
+            link.is_running
+        );
+    }
+
+    fn update_address(&mut self, info: &AddressInfo) {
+        let network = match info.addrs.interface_network() {
+            Ok(Some(net)) => net,
+            Ok(None) => return,
+            Err(e) => {
+                log::warn!("ignoring address with bad netmask: {e}");
+                return;
+            }
+        };
+
+        // Make sure we know about the interface
+        self.link_mut(info.index);
+
+        let ip = network.ip();
+        self.addresses
+            .retain(|a| !(a.index == info.index && a.network.ip() == ip));
+
+        match info.operation {
+            AddressOperation::Add if info.flags.is_up() && !info.flags.is_dead() => {
+                log::debug!("address {network} added to {}", info.index);
+                self.addresses.push(Address {
+                    index: info.index,
+                    network,
+                    ipv6_flags: read_ipv6_flags(info.index, ip),
+                    arp_collision: false,
+                });
+            }
+            _ => log::debug!("address {network} removed from {}", info.index),
+        }
+    }
+
+    /// Whether any IPv6 address is still going through duplicate address
+    /// detection.
+    pub fn has_pending_dad(&self) -> bool {
+        self.addresses.iter().any(|a| {
+            a.ipv6_flags
+                .is_some_and(|f| f.is_tentative() || f.is_optimistic())
+        })
+    }
+
+    /// Addresses which another host was found to be using.
+    pub fn duplicated_addresses(&self) -> impl Iterator<Item = &Address> {
+        self.addresses.iter().filter(|a| a.is_duplicated())
+    }
+
+    /// Marks an IPv4 address as in use by another host. It stays that way
+    /// until it's removed or added again.
+    pub fn add_arp_collision(&mut self, collision: &ArpCollision) {
+        let ip = IpAddr::V4(collision.ip);
+        for a in self.addresses.iter_mut().filter(|a| a.network.ip() == ip) {
+            let name = self.links.get(&a.index).and_then(|l| l.name.as_deref());
+            if name.is_none_or(|n| n == collision.interface_name) {
+                a.arp_collision = true;
+            }
+        }
+    }
+
+    /// Reads the flags of addresses going through duplicate address
+    /// detection again. The kernel doesn't always send a message when it
+    /// finishes, so this has to be polled.
+    pub fn refresh_pending_dad(&mut self) {
+        for a in self.addresses.iter_mut() {
+            let (flags, ip) = match (a.ipv6_flags, a.network.ip()) {
+                (Some(f), IpAddr::V6(ip)) if f.is_tentative() || f.is_optimistic() => (f, ip),
+                _ => continue,
+            };
+            match address_flags(a.index, ip) {
+                Ok(new) if new != flags => {
+                    log::debug!("{ip} on {} is now {:?}", a.index, new.names());
+                    a.ipv6_flags = Some(new);
+                }
+                Ok(_) => (),
+                Err(e) => log::debug!("failed to get flags for {ip}: {e}"),
+            }
+        }
+    }
+
+    pub fn links(&self) -> impl Iterator<Item = &Link> {
+        self.links.values()
+    }
+
+    /// The interface's name, or its index if we don't know it.
+    pub fn interface_name(&self, index: u16) -> String {
+        self.links
+            .get(&index)
+            .and_then(|l| l.name.clone())
+            .unwrap_or_else(|| format!("index:{index}"))
+    }
+
+    /// Whether the interface is both up and running (i.e. has a carrier).
+    pub fn is_link_up(&self, index: u16) -> bool {
+        self.links
+            .get(&index)
+            .map(|l| l.is_up && l.is_running)
+            .unwrap_or(false)
+    }
+
+    pub fn addresses(&self) -> impl Iterator<Item = &Address> {
+        self.addresses.iter()
+    }
+
+    pub fn routes(&self) -> impl Iterator<Item = &Route> {
+        self.routes.routes()
+    }
+
+    /// The route traffic to `ip` would currently take.
+    pub fn lookup_route(&self, ip: IpAddr) -> Option<&Route> {
+        self.routes.lookup(ip)
+    }
+
+    /// Whether traffic to `ip` has somewhere to go, i.e. isn't rejected or
+    /// without a route entirely.
+    pub fn is_routable(&self, ip: IpAddr) -> bool {
+        matches!(self.lookup_route(ip), Some(r) if !r.is_reject)
+    }
+
+    pub fn policy(&self) -> &AddressPolicy {
+        &self.policy
+    }
+
+    pub fn neighbors(&self) -> &NeighborTable {
+        &self.neighbors
+    }
+
+    pub fn set_nameservers(&mut self, nameservers: Vec<IpAddr>) {
+        log::debug!("nameservers: {nameservers:?}");
+        self.nameservers = nameservers;
+    }
+
+    pub fn nameservers(&self) -> &[IpAddr] {
+        &self.nameservers
+    }
+}
+
+/// Gives the gateway of a default route being added by this event, if any.
+pub fn new_default_gateway(h: &Header) -> Option<IpAddr> {
+    let info = match h {
+        Header::Route(info) => info,
+        _ => return None,
+    };
+    if !matches!(
+        info.operation,
+        RouteMessageType::Add | RouteMessageType::Change
+    ) {
+        return None;
+    }
+
+    match info.addrs.destination_network() {
+        Ok(Some(net)) if net.prefix() == 0 => info.addrs.gateway.as_ref()?.ip(),
+        _ => None,
+    }
+}
+
+/// Reads the DAD state of a newly added IPv6 address. If it can't be read,
+/// the address counts as still tentative, so it's read again shortly rather
+/// than being taken as usable.
+fn read_ipv6_flags(index: u16, ip: IpAddr) -> Option<Ipv6AddressFlags> {
+    let ip = match ip {
+        IpAddr::V6(ip) => ip,
+        IpAddr::V4(_) => return None,
+    };
+    match address_flags(index, ip) {
+        Ok(flags) => Some(flags),
+        Err(e) => {
+            log::debug!("failed to get flags for {ip}, counting it as tentative: {e}");
+            Some(Ipv6AddressFlags::tentative())
+        }
+    }
+}
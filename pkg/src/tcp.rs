@@ -0,0 +1,187 @@
+//! Checking that a TCP service accepts connections.
+
+use std::net::{TcpStream, ToSocketAddrs};
+use std::time::{Duration, Instant};
+use std::{fmt, io};
+
+use crate::flags::parse_duration;
+use crate::probe::{Comparison, Probe, Samples, Stats, Thresholds, DEFAULT_WINDOW};
+
+/// How long each attempt gets, unless given with `timeout=`
+const DEFAULT_CONNECT_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// `tcp=host:port` and its options.
+#[derive(Clone, Debug)]
+pub struct TcpOptions {
+    /// `host:port`, where the host may be a name, IPv4 address or bracketed
+    /// IPv6 address
+    pub target: String,
+    /// How long each attempt gets, from resolving the host to the handshake
+    /// completing. This is separate from --timeout, which covers the whole
+    /// wait.
+    pub timeout: Duration,
+    pub thresholds: Thresholds,
+}
+
+impl TcpOptions {
+    pub fn new(target: &str) -> Result<Self, String> {
+        let port = target
+            .rsplit_once(':')
+            .and_then(|(host, port)| (!host.is_empty()).then_some(port));
+        match port.map(str::parse::<u16>) {
+            Some(Ok(_)) => Ok(Self {
+                target: target.to_string(),
+                timeout: DEFAULT_CONNECT_TIMEOUT,
+                thresholds: Thresholds::default(),
+            }),
+            _ => Err(format!("invalid target '{target}' (expected host:port)")),
+        }
+    }
+
+    /// Applies a `key=value` option.
+    pub fn qualify(&mut self, key: &str, value: &str) -> Result<(), String> {
+        match key {
+            "timeout" => {
+                self.timeout = parse_duration(value)?;
+                Ok(())
+            }
+            "window" => self.thresholds.set_window(value),
+            _ => Err(format!(
+                "unknown option '{key}' (expected timeout or window)"
+            )),
+        }
+    }
+
+    /// Applies a `key<value` or `key<=value` limit.
+    pub fn limit(&mut self, key: &str, comparison: Comparison, value: &str) -> Result<(), String> {
+        self.thresholds.limit(key, comparison, value)
+    }
+}
+
+impl fmt::Display for TcpOptions {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.target)?;
+        if self.timeout != DEFAULT_CONNECT_TIMEOUT {
+            write!(f, ",timeout={:?}", self.timeout)?;
+        }
+        write!(f, "{}", self.thresholds)
+    }
+}
+
+/// The `tcp` condition, along with its probe's progress.
+#[derive(Debug)]
+pub struct TcpCheck {
+    pub options: TcpOptions,
+    probe: Probe<Duration>,
+    samples: Samples,
+    connected: bool,
+}
+
+impl TcpCheck {
+    pub fn new(options: TcpOptions) -> Self {
+        let window = options.thresholds.window.unwrap_or(DEFAULT_WINDOW);
+        Self {
+            probe: Probe::new(options.timeout),
+            options,
+            samples: Samples::new(window),
+            connected: false,
+        }
+    }
+
+    pub fn holds(&mut self) -> bool {
+        if let Some(result) = self.probe.finished() {
+            let rtt = match result {
+                Ok(rtt) => Some(rtt),
+                Err(e) => {
+                    log::debug!("couldn't connect to {}: {e}", self.options.target);
+                    None
+                }
+            };
+            self.samples.record(rtt);
+            self.connected = rtt.is_some() && self.options.thresholds.allow(&self.samples);
+            self.probe.record(self.connected);
+        }
+        if self.probe.is_due() {
+            let target = self.options.target.clone();
+            self.probe.start(move |deadline| connect(&target, deadline));
+        }
+        self.connected
+    }
+
+    /// Round trip times and loss over the window.
+    pub fn stats(&self) -> Stats {
+        self.samples.stats()
+    }
+
+    /// How long until the probe should be checked on.
+    pub fn next_probe_in(&self) -> Option<Duration> {
+        self.probe.next_check_in()
+    }
+
+    pub fn network_changed(&mut self) {
+        self.probe.network_changed();
+    }
+}
+
+/// Connects to each address `target` resolves to in turn, and succeeds as
+/// soon as a handshake completes, giving how long it took. The connection is
+/// closed straight away. All of the addresses share one deadline.
+fn connect(target: &str, deadline: Instant) -> io::Result<Duration> {
+    let mut last_err = None;
+    for addr in target.to_socket_addrs()? {
+        let left = deadline.saturating_duration_since(Instant::now());
+        if left.is_zero() {
+            return Err(io::ErrorKind::TimedOut.into());
+        }
+
+        let start = Instant::now();
+        match TcpStream::connect_timeout(&addr, left) {
+            Ok(_) => {
+                let rtt = start.elapsed();
+                log::debug!("connected to {target} ({addr}) in {rtt:?}");
+                return Ok(rtt);
+            }
+            Err(e) => last_err = Some(e),
+        }
+    }
+
+    Err(last_err.unwrap_or_else(|| io::Error::new(io::ErrorKind::NotFound, "no addresses found")))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{Ipv4Addr, TcpListener};
+
+    use super::*;
+
+    fn deadline() -> Instant {
+        Instant::now() + Duration::from_secs(1)
+    }
+
+    #[test]
+    fn connects() {
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let target = listener.local_addr().unwrap().to_string();
+        let rtt = connect(&target, deadline()).unwrap();
+        assert!(rtt < Duration::from_secs(1));
+    }
+
+    #[test]
+    fn refused() {
+        // Nothing listens on the port once the listener is gone
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let target = listener.local_addr().unwrap().to_string();
+        drop(listener);
+
+        let err = connect(&target, deadline()).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::ConnectionRefused);
+    }
+
+    #[test]
+    fn past_deadline() {
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let target = listener.local_addr().unwrap().to_string();
+        let err = connect(&target, Instant::now()).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+    }
+}